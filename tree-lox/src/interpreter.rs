@@ -433,7 +433,7 @@ fn lox_is_equal(a: &LoxValue, b: &LoxValue) -> bool {
     match (a, b) {
         (Function(a), Function(b)) =>
         {
-            #[allow(clippy::vtable_address_comparisons)]
+            #[allow(ambiguous_wide_pointer_comparisons)]
             Rc::ptr_eq(a, b)
         }
         (Class(a), Class(b)) => Rc::ptr_eq(a, b),
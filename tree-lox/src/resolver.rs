@@ -289,9 +289,8 @@ impl<'i> Resolver<'i> {
         I: FnOnce(&mut Self),
     {
         self.begin_scope();
-        let res = inner(self);
+        inner(self);
         self.end_scope();
-        res
     }
 
     fn error(&mut self, span: Span, message: impl Into<String>) {
@@ -0,0 +1,99 @@
+//! A micro-benchmark of the virtual machine's instruction dispatch.
+//!
+//! Each program is dominated by the execution of simple instructions (arithmetic, local and global
+//! variable accesses, jumps, calls and method invocations), so that the dispatch loop is the
+//! bottleneck. Run with:
+//!
+//! ```text
+//! cargo bench -p vm-lox --bench dispatch
+//! ```
+//!
+//! For reference, the best times of three interleaved runs on the same machine, with the
+//! instructions stored as a `Vec<Ins>` (before the byte encoding, hence also before the method
+//! caches), with the bytes read through the current frame, and with the current chunk and
+//! instruction pointer kept in locals of the dispatch loop (see `Vm::execute`):
+//!
+//! ```text
+//! program            Vec<Ins>     bytes via frame   bytes in locals
+//! arithmetic loop    179-213ms    214-231ms         124-162ms
+//! local variables    105-135ms    174-184ms          80-131ms
+//! recursive calls     20-29ms      32-36ms           21-27ms
+//! method calls       116-119ms     85-101ms          46-69ms
+//! ```
+
+use std::time::{Duration, Instant};
+
+use vm_lox::{interpret_in, Vm};
+
+const RUNS: u32 = 5;
+
+const PROGRAMS: &[(&str, &str)] = &[
+    (
+        "arithmetic loop",
+        "
+        var sum = 0;
+        for (var i = 0; i < 1000000; i = i + 1) {
+            sum = sum + i * 2 - i / 2;
+        }
+        ",
+    ),
+    (
+        "local variables",
+        "
+        {
+            var a = 1;
+            var b = 2;
+            for (var i = 0; i < 1000000; i = i + 1) {
+                var c = a;
+                a = b;
+                b = c;
+            }
+        }
+        ",
+    ),
+    (
+        "recursive calls",
+        "
+        fun fib(n) {
+            if (n < 2) return n;
+            return fib(n - 2) + fib(n - 1);
+        }
+        fib(25);
+        ",
+    ),
+    (
+        "method calls",
+        "
+        class Counter {
+            init() { this.count = 0; }
+            increment() { this.count = this.count + 1; }
+        }
+        var counter = Counter();
+        for (var i = 0; i < 300000; i = i + 1) {
+            counter.increment();
+        }
+        ",
+    ),
+];
+
+fn main() {
+    println!("{:<20} {:>12} {:>12}", "program", "best", "mean");
+    for (name, source) in PROGRAMS {
+        let mut best = Duration::MAX;
+        let mut total = Duration::ZERO;
+        for _ in 0..RUNS {
+            let mut vm = Vm::new();
+            let start = Instant::now();
+            interpret_in(&mut vm, source).expect("benchmark program should not fail");
+            let elapsed = start.elapsed();
+            best = best.min(elapsed);
+            total += elapsed;
+        }
+        println!(
+            "{:<20} {:>10.2}ms {:>10.2}ms",
+            name,
+            best.as_secs_f64() * 1e3,
+            (total / RUNS).as_secs_f64() * 1e3
+        );
+    }
+}
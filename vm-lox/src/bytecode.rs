@@ -0,0 +1,497 @@
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    str,
+};
+
+use crate::{
+    common::{
+        Capture, Chunk, Function, Handler, LineRun, ObjectKind, ObjectRef, Span, Unpacked, Value,
+    },
+    vm::Vm,
+};
+
+// The bytecode file format (`.loxc`).
+//
+// # Layout:
+//
+// -----------------------------------------------------------------------------
+//
+// A file is laid out as follows. Every integer is encoded in big-endian order, strings are
+// encoded as their UTF-8 bytes preceded by their `u32` length, and sequences are preceded by
+// their `u32` number of items.
+//
+// file      ::= "LOXC" version:u16 source_name:string function ;
+// function  ::= name:string arity:u8 (is_local:u8 index:u8)* code:u8* line_run* constant*
+//               caches:u32 handler* ;
+// line_run  ::= start:u32 line:u32 lo:u32 hi:u32 ;
+// handler   ::= start:u32 end:u32 target:u32 depth:u16 ;
+// constant  ::= 0x00                      (nil)
+//             | 0x01 | 0x02               (false, true)
+//             | 0x03 bits:u64             (number)
+//             | 0x04 string               (string)
+//             | 0x05 function             (function) ;
+//
+// The top-level function is the script. Nested functions are stored in the constant table of
+// the function which defines them. Since the inline caches are only filled at runtime, only their
+// number is stored.
+
+/// The bytes every bytecode file starts with.
+pub const MAGIC: &[u8; 4] = b"LOXC";
+
+/// The version of the format. Since the instructions are stored as they are encoded in the
+/// chunks, it must be incremented whenever their encoding changes (e.g. an opcode is added).
+pub const VERSION: u16 = 5;
+
+/// The maximum nesting depth of functions which is accepted while loading a file.
+const DEPTH_MAX: usize = 256;
+
+/// The maximum number of inline caches of a function (see `Ins::Invoke`).
+const CACHES_MAX: usize = u16::MAX as usize + 1;
+
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_FUNCTION: u8 = 5;
+
+/// Encodes the chunk of the given script, compiled from the given source file.
+pub fn serialize(chunk: &Chunk, source_name: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&VERSION.to_be_bytes());
+    write_str(&mut out, source_name);
+    write_function(&mut out, 0, &[], chunk);
+    out
+}
+
+/// Decodes the chunk of the script of the given bytecode file. Its objects (such as string
+/// constants and nested functions) are allocated in the heap of the given virtual machine.
+pub fn deserialize(bytes: &[u8], vm: &mut Vm) -> Result<Chunk, LoadError> {
+    let mut loader = Loader {
+        bytes,
+        pos: 0,
+        vm,
+        objects: Vec::new(),
+    };
+    loader.header()?;
+    // The name of the source file is only informative, hence it is skipped.
+    loader.string()?;
+    let (_, _, chunk) = loader.function(0)?;
+    if loader.pos != bytes.len() {
+        return Err(loader.error(LoadErrorKind::TrailingBytes));
+    }
+    Ok(chunk)
+}
+
+/// Represents an error which occurred while loading a bytecode file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoadError {
+    pub kind: LoadErrorKind,
+    /// The offset of the byte at which the error was detected.
+    pub offset: usize,
+}
+
+/// Represents the different kinds of errors which may occur while loading a bytecode file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadErrorKind {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    TrailingBytes,
+    InvalidUtf8,
+    InvalidTag(u8),
+    InvalidLineTable,
+    TooDeep,
+    TooManyCaches,
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use LoadErrorKind::*;
+        match self.kind {
+            BadMagic => f.write_str("Not a Lox bytecode file (bad header)")?,
+            UnsupportedVersion(version) => write!(
+                f,
+                "Unsupported bytecode format version {version} (expected version {VERSION})"
+            )?,
+            Truncated => f.write_str("Unexpected end of file (truncated bytecode)")?,
+            TrailingBytes => f.write_str("Unexpected trailing bytes after the script")?,
+            InvalidUtf8 => f.write_str("Invalid UTF-8 string")?,
+            InvalidTag(tag) => write!(f, "Invalid constant tag {tag:#04x}")?,
+            InvalidLineTable => f.write_str("Invalid line table")?,
+            TooDeep => f.write_str("Functions nested too deeply")?,
+            TooManyCaches => f.write_str("Too many inline caches")?,
+        }
+        write!(f, "; at byte {}", self.offset)
+    }
+}
+
+impl Error for LoadError {}
+
+fn write_u32(out: &mut Vec<u8>, n: usize) {
+    let n = u32::try_from(n).expect("Length doesn't fit the bytecode format");
+    out.extend_from_slice(&n.to_be_bytes());
+}
+
+fn write_str(out: &mut Vec<u8>, string: &str) {
+    write_u32(out, string.len());
+    out.extend_from_slice(string.as_bytes());
+}
+
+fn write_function(out: &mut Vec<u8>, arity: u8, captures: &[Capture], chunk: &Chunk) {
+    write_str(out, chunk.name());
+    out.push(arity);
+
+    write_u32(out, captures.len());
+    for capture in captures {
+        out.push(capture.is_local as u8);
+        out.push(capture.index);
+    }
+
+    write_u32(out, chunk.code.len());
+    out.extend_from_slice(&chunk.code);
+
+    write_u32(out, chunk.lines.len());
+    for run in &chunk.lines {
+        out.extend_from_slice(&run.start.to_be_bytes());
+        out.extend_from_slice(&run.line.to_be_bytes());
+        out.extend_from_slice(&(run.span.lo as u32).to_be_bytes());
+        out.extend_from_slice(&(run.span.hi as u32).to_be_bytes());
+    }
+
+    write_u32(out, chunk.constants.len());
+    for &constant in &chunk.constants {
+        match constant.unpack() {
+            Unpacked::Nil => out.push(TAG_NIL),
+            Unpacked::Bool(false) => out.push(TAG_FALSE),
+            Unpacked::Bool(true) => out.push(TAG_TRUE),
+            Unpacked::Number(number) => {
+                out.push(TAG_NUMBER);
+                out.extend_from_slice(&number.to_bits().to_be_bytes());
+            }
+            Unpacked::Object(object) => match &object.kind {
+                ObjectKind::String(string) => {
+                    out.push(TAG_STRING);
+                    write_str(out, string);
+                }
+                ObjectKind::Function(function) => {
+                    out.push(TAG_FUNCTION);
+                    write_function(out, function.arity, &function.captures, &function.chunk);
+                }
+                _ => unreachable!("Compiler bug. Unexpected `{object}` constant"),
+            },
+        }
+    }
+
+    write_u32(out, chunk.caches.len());
+
+    write_u32(out, chunk.handlers.len());
+    for handler in &chunk.handlers {
+        out.extend_from_slice(&handler.start.to_be_bytes());
+        out.extend_from_slice(&handler.end.to_be_bytes());
+        out.extend_from_slice(&handler.target.to_be_bytes());
+        out.extend_from_slice(&handler.depth.to_be_bytes());
+    }
+}
+
+struct Loader<'b, 'v> {
+    bytes: &'b [u8],
+    pos: usize,
+    vm: &'v mut Vm,
+    /// The objects allocated during the loading. Since they are referenced by the chunks being
+    /// loaded, they are the loader's garbage collection roots.
+    objects: Vec<ObjectRef>,
+}
+
+impl<'b> Loader<'b, '_> {
+    fn header(&mut self) -> Result<(), LoadError> {
+        if self.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            self.pos = 0;
+            return Err(self.error(LoadErrorKind::BadMagic));
+        }
+        let version = u16::from_be_bytes(self.array()?);
+        if version != VERSION {
+            return Err(self.error(LoadErrorKind::UnsupportedVersion(version)));
+        }
+        Ok(())
+    }
+
+    fn function(&mut self, depth: usize) -> Result<(u8, Vec<Capture>, Chunk), LoadError> {
+        if depth > DEPTH_MAX {
+            return Err(self.error(LoadErrorKind::TooDeep));
+        }
+        let mut chunk = Chunk::new(self.string()?);
+        let arity = self.u8()?;
+
+        let mut captures = Vec::new();
+        for _ in 0..self.u32()? {
+            let is_local = self.u8()? != 0;
+            let index = self.u8()?;
+            captures.push(Capture { is_local, index });
+        }
+
+        let len = self.u32()? as usize;
+        chunk.code = self.take(len)?.to_vec();
+
+        let line_table_pos = self.pos;
+        for _ in 0..self.u32()? {
+            let start = self.u32()?;
+            let line = self.u32()?;
+            let span = Span::new(self.u32()? as usize, self.u32()? as usize);
+            chunk.lines.push(LineRun { start, line, span });
+        }
+        // Every byte of the code must be covered by the runs, so that its line may be queried.
+        let starts_ordered = chunk.lines.windows(2).all(|w| w[0].start < w[1].start);
+        let covers_code = match (chunk.lines.first(), chunk.lines.last()) {
+            (Some(first), Some(last)) => first.start == 0 && (last.start as usize) < len,
+            _ => len == 0,
+        };
+        if !starts_ordered || !covers_code {
+            self.pos = line_table_pos;
+            return Err(self.error(LoadErrorKind::InvalidLineTable));
+        }
+
+        for _ in 0..self.u32()? {
+            let constant = self.constant(depth)?;
+            chunk.add_constant(constant);
+        }
+
+        let caches_pos = self.pos;
+        let caches = self.u32()? as usize;
+        if caches > CACHES_MAX {
+            self.pos = caches_pos;
+            return Err(self.error(LoadErrorKind::TooManyCaches));
+        }
+        for _ in 0..caches {
+            chunk.add_cache();
+        }
+
+        // The handlers are checked by the verifier, alongside with the code.
+        for _ in 0..self.u32()? {
+            let start = self.u32()?;
+            let end = self.u32()?;
+            let target = self.u32()?;
+            let depth = u16::from_be_bytes(self.array()?);
+            chunk.handlers.push(Handler {
+                start,
+                end,
+                target,
+                depth,
+            });
+        }
+        Ok((arity, captures, chunk))
+    }
+
+    fn constant(&mut self, depth: usize) -> Result<Value, LoadError> {
+        Ok(match self.u8()? {
+            TAG_NIL => Value::NIL,
+            TAG_FALSE => Value::from(false),
+            TAG_TRUE => Value::from(true),
+            TAG_NUMBER => Value::from(f64::from_bits(u64::from_be_bytes(self.array()?))),
+            TAG_STRING => {
+                let string = self.string()?;
+                self.mark_roots();
+                let object = self.vm.intern(string);
+                self.objects.push(object);
+                Value::from(object)
+            }
+            TAG_FUNCTION => {
+                let (arity, captures, chunk) = self.function(depth + 1)?;
+                let kind = ObjectKind::Function(Function::new(arity, captures, chunk));
+                self.mark_roots();
+                let object = self.vm.alloc(kind);
+                self.objects.push(object);
+                Value::from(object)
+            }
+            tag => {
+                self.pos -= 1;
+                return Err(self.error(LoadErrorKind::InvalidTag(tag)));
+            }
+        })
+    }
+
+    /// Marks the loader roots, if a garbage collection is about to be performed.
+    fn mark_roots(&mut self) {
+        if self.vm.heap.should_collect() {
+            for &object in &self.objects {
+                self.vm.heap.mark_object(object);
+            }
+        }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'b [u8], LoadError> {
+        let bytes = self
+            .bytes
+            .get(self.pos..)
+            .and_then(|rest| rest.get(..len))
+            .ok_or_else(|| self.error(LoadErrorKind::Truncated))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], LoadError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, LoadError> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    fn string(&mut self) -> Result<&'b str, LoadError> {
+        let len = self.u32()? as usize;
+        let start = self.pos;
+        let bytes = self.take(len)?;
+        str::from_utf8(bytes).map_err(|_| {
+            self.pos = start;
+            self.error(LoadErrorKind::InvalidUtf8)
+        })
+    }
+
+    fn error(&self, kind: LoadErrorKind) -> LoadError {
+        LoadError {
+            kind,
+            offset: self.pos,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::Ins,
+        pipeline::{compile_bytecode, interpret_bytecode, Error, RuntimeError},
+    };
+
+    const SOURCE: &str = "
+        class A { init(x) { this.x = x; } get() { return this.x; } }
+        class B < A { get() { return super.get() + 1; } }
+        fun counter() { var n = 0; fun next() { n = n + 1; return n; } return next; }
+        var next = counter();
+        next();
+        try { throw B(next()).get(); } catch (e) { print e; }
+    ";
+
+    fn compiled() -> Vec<u8> {
+        compile_bytecode(&mut Vm::new(), SOURCE, "test.lox").unwrap()
+    }
+
+    /// Returns the bytecode file of a script with the given code and constants.
+    fn file(code: &[Ins], constants: &[Value]) -> Vec<u8> {
+        let mut chunk = Chunk::new("<script>");
+        for &ins in code {
+            chunk.write(ins, 1, Span::default());
+        }
+        for &constant in constants {
+            chunk.add_constant(constant);
+        }
+        serialize(&chunk, "test.lox")
+    }
+
+    fn load_error(bytes: &[u8]) -> LoadErrorKind {
+        deserialize(bytes, &mut Vm::new()).err().unwrap().kind
+    }
+
+    #[test]
+    fn round_trips() {
+        let bytes = compiled();
+        let mut vm = Vm::new();
+        let chunk = deserialize(&bytes, &mut vm).unwrap();
+        assert_eq!(serialize(&chunk, "test.lox"), bytes);
+        interpret_bytecode(&mut vm, &bytes).unwrap();
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let mut bytes = compiled();
+        bytes[0] = b'X';
+        assert_eq!(load_error(&bytes), LoadErrorKind::BadMagic);
+        let mut bytes = compiled();
+        bytes[4..6].copy_from_slice(&(VERSION - 1).to_be_bytes());
+        assert_eq!(
+            load_error(&bytes),
+            LoadErrorKind::UnsupportedVersion(VERSION - 1)
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_and_trailing_bytes() {
+        let bytes = compiled();
+        for len in MAGIC.len()..bytes.len() {
+            assert_eq!(load_error(&bytes[..len]), LoadErrorKind::Truncated);
+        }
+        let mut bytes = bytes;
+        bytes.push(0);
+        assert_eq!(load_error(&bytes), LoadErrorKind::TrailingBytes);
+    }
+
+    #[test]
+    fn rejects_malformed_functions() {
+        use Ins::*;
+        // The only constant is followed by the caches and handlers counts.
+        let mut bytes = file(&[Constant(0), Return], &[Value::NIL]);
+        let tag = bytes.len() - 9;
+        bytes[tag] = 0x07;
+        assert_eq!(load_error(&bytes), LoadErrorKind::InvalidTag(0x07));
+
+        let mut chunk = Chunk::new("<script>");
+        chunk.write(Nil, 1, Span::default());
+        chunk.write(Return, 1, Span::default());
+        chunk.lines[0].start = 1;
+        let bytes = serialize(&chunk, "test.lox");
+        assert_eq!(load_error(&bytes), LoadErrorKind::InvalidLineTable);
+
+        chunk.lines[0].start = 0;
+        for _ in 0..=CACHES_MAX {
+            chunk.add_cache();
+        }
+        let bytes = serialize(&chunk, "test.lox");
+        assert_eq!(load_error(&bytes), LoadErrorKind::TooManyCaches);
+    }
+
+    #[test]
+    fn verifies_loaded_code() {
+        let bytes = file(&[Ins::Pop, Ins::Pop, Ins::Nil, Ins::Return], &[]);
+        let result = interpret_bytecode(&mut Vm::new(), &bytes);
+        assert!(matches!(result, Err(Error::VerifyError(_))), "{result:?}");
+    }
+
+    #[test]
+    fn reports_ill_typed_code_at_runtime() {
+        use Ins::*;
+        let mut vm = Vm::new();
+        let name = Value::from(vm.heap.intern("m"));
+        let files = [
+            file(&[Nil, Nil, GetSuper(0), Pop, Nil, Return], &[name]),
+            file(&[Class(0), Nil, Method(0), Pop, Nil, Return], &[name]),
+        ];
+        for bytes in files {
+            let result = interpret_bytecode(&mut Vm::new(), &bytes);
+            assert!(
+                matches!(result, Err(Error::RuntimeError(RuntimeError { .. }))),
+                "{result:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn never_panics_on_corrupted_files() {
+        let bytes = compiled();
+        for pos in 0..bytes.len() {
+            for byte in [0x00, 0x01, 0x7f, 0xff, bytes[pos] ^ 0x10] {
+                let mut corrupted = bytes.clone();
+                corrupted[pos] = byte;
+                let mut vm = Vm::new();
+                vm.set_max_instructions(Some(10_000));
+                // Any outcome is fine, as long as it is reported without panicking.
+                let _ = interpret_bytecode(&mut vm, &corrupted);
+            }
+        }
+    }
+}
@@ -1,12 +1,85 @@
-use std::fmt::{self, Debug};
+use std::{
+    cell::Cell,
+    fmt::{self, Debug},
+};
 
-use crate::common::Ins;
+use crate::{
+    common::{Ins, ObjectRef, Span, Value},
+    disassembler::Disassembler,
+};
 
-/// Represents a chunk of bytecode. A sequence of instructions.
+/// Represents a chunk of bytecode. A sequence of encoded instructions (see `Ins`), alongside with
+/// the constants they refer to.
 pub struct Chunk {
     name: String,
-    pub(crate) code: Vec<Ins>,
-    pub(crate) lines: Vec<u32>,
+    pub(crate) code: Vec<u8>,
+    pub(crate) constants: Vec<Value>,
+    /// The source lines and spans of the code, run-length encoded. See `line_at` and `span_at`.
+    pub(crate) lines: Vec<LineRun>,
+    /// The inline caches of the property lookups, referred to by the instructions which perform
+    /// them (see `Ins::Invoke`). Each cache holds the last property found by its instruction.
+    pub(crate) caches: Vec<Cell<Option<InlineCache>>>,
+    /// The exception handlers of the code. Since they are looked up in order, the handlers of
+    /// nested `try` statements precede the enclosing ones.
+    pub(crate) handlers: Vec<Handler>,
+}
+
+/// A property found by some property lookup, alongside with the class it was looked up in.
+///
+/// The class is a weak reference: the heap clears the caches of the classes it frees (see
+/// `Heap::collect`), so that a class later allocated at the same address doesn't hit them.
+#[derive(Copy, Clone)]
+pub struct InlineCache {
+    pub(crate) class: ObjectRef,
+    pub(crate) property: CachedProperty,
+}
+
+/// The property held by an inline cache.
+#[derive(Copy, Clone)]
+pub enum CachedProperty {
+    /// A field, stored at the given entry of the fields table of the instance it was found in.
+    /// Since the instances of a class usually have the same fields, the entry is checked first
+    /// by the next lookups on instances of the same class.
+    Field(usize),
+    /// A method of the class. Since the methods of a class never change once it is defined (and
+    /// are kept alive by it), the method is reused by the next lookups in the same class.
+    Method(ObjectRef),
+}
+
+/// An exception handler, i.e. the `catch` block of a `try` statement.
+///
+/// When an exception is thrown by an instruction in the range of the handler (the `try` block),
+/// or by some function it calls, the stack is truncated to the given height, the exception is
+/// pushed, and the execution continues at the target of the handler.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Handler {
+    /// The offset of the first byte of the range.
+    pub(crate) start: u32,
+    /// The offset which follows the last byte of the range.
+    pub(crate) end: u32,
+    /// The offset of the first instruction of the `catch` block.
+    pub(crate) target: u32,
+    /// The height of the stack (relative to the first slot of the frame) when the range is
+    /// entered, i.e. the number of local variables in scope.
+    pub(crate) depth: u16,
+}
+
+impl Handler {
+    /// Checks if the byte at the given offset is in the range of the handler.
+    pub fn covers(&self, offset: usize) -> bool {
+        (self.start as usize..self.end as usize).contains(&offset)
+    }
+}
+
+/// A sequence of bytes of the code which originate from the same source span (hence line).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LineRun {
+    /// The offset of the first byte of the run. The run extends until the start of the next one
+    /// (or the end of the code).
+    pub(crate) start: u32,
+    pub(crate) line: u32,
+    /// The source span of the expression (or statement) the instructions of the run evaluate.
+    pub(crate) span: Span,
 }
 
 impl Chunk {
@@ -15,38 +88,66 @@ impl Chunk {
         Self {
             name: name.into(),
             code: Vec::new(),
+            constants: Vec::new(),
             lines: Vec::new(),
+            caches: Vec::new(),
+            handlers: Vec::new(),
         }
     }
 
-    /// Writes an instruction to the chunk's bytecode.
-    pub fn write(&mut self, ins: Ins, line: u32) {
-        debug_assert_eq!(
-            self.code.len(),
-            self.lines.len(),
-            "Not parallel lengths of code and lines vectors"
-        );
+    /// Returns the chunk name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
 
-        self.code.push(ins);
-        self.lines.push(line);
+    /// Encodes an instruction to the chunk's bytecode, attributing it to the given source line
+    /// and span.
+    pub fn write(&mut self, ins: Ins, line: u32, span: Span) {
+        if self.lines.last().map(|run| (run.line, run.span)) != Some((line, span)) {
+            self.lines.push(LineRun {
+                start: self.code.len() as u32,
+                line,
+                span,
+            });
+        }
+        ins.encode(&mut self.code);
+    }
+
+    /// Adds a constant to the chunk's constant table, returning its index.
+    pub fn add_constant(&mut self, value: Value) -> usize {
+        self.constants.push(value);
+        self.constants.len() - 1
+    }
+
+    /// Adds an empty inline cache to the chunk, returning its index.
+    pub fn add_cache(&mut self) -> usize {
+        self.caches.push(Cell::new(None));
+        self.caches.len() - 1
+    }
+
+    /// Returns the source line of the byte at the given offset.
+    pub fn line_at(&self, offset: usize) -> u32 {
+        self.run_at(offset).line
+    }
+
+    /// Returns the source span of the byte at the given offset.
+    pub fn span_at(&self, offset: usize) -> Span {
+        self.run_at(offset).span
+    }
+
+    /// Returns the run of the line table which covers the byte at the given offset.
+    fn run_at(&self, offset: usize) -> &LineRun {
+        debug_assert!(offset < self.code.len(), "Offset out of the code bounds");
+        // The index of the first run which starts after the offset.
+        let next = self
+            .lines
+            .partition_point(|run| run.start as usize <= offset);
+        &self.lines[next - 1]
     }
 }
 
 impl Debug for Chunk {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        writeln!(f, "=== {} ===", self.name)?;
-
-        let mut last_line = 0;
-        for (ins, &line) in self.code.iter().zip(&self.lines) {
-            if last_line != line {
-                write!(f, "{line:>5}")?;
-                last_line = line;
-            } else {
-                f.write_str("    .")?;
-            }
-            writeln!(f, " | {ins:?}")?;
-        }
-
-        Ok(())
+        Disassembler::new(f).chunk(self)
     }
 }
@@ -0,0 +1,240 @@
+use std::{cell::Cell, mem, ptr::NonNull};
+
+use crate::common::{
+    table::hash_string, LoxString, Object, ObjectKind, ObjectRef, Table, UpvalueState, Value,
+};
+
+/// The number of allocated bytes which triggers the first collection.
+const INITIAL_NEXT_GC: usize = 1024 * 1024;
+
+/// After each collection, the next one is triggered once the heap grows by this factor.
+const HEAP_GROW_FACTOR: usize = 2;
+
+/// The object heap. Owns every object allocated during the program execution (including the
+/// ones allocated by the compiler, such as string literals).
+///
+/// Unreachable objects are reclaimed by a tracing mark-sweep garbage collector. The heap itself
+/// doesn't know which objects are reachable, hence the collection is driven by the owners of the
+/// roots (i.e. the VM and the compiler), which must mark them before calling `collect`:
+///
+/// ```ignore
+/// if heap.should_collect() {
+///     heap.mark_value(some_root);
+///     heap.collect();
+/// }
+/// ```
+pub struct Heap {
+    objects: Vec<ObjectRef>,
+    /// The interned strings (every string object). The table holds weak references, i.e. it
+    /// doesn't keep its strings alive. Unreachable strings are removed from it during the
+    /// collection.
+    strings: Table<()>,
+    /// The marked objects whose references are yet to be traced.
+    gray: Vec<ObjectRef>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    /// If true, a collection is performed before every allocation.
+    pub(crate) stress: bool,
+    /// If true, the number of allocated bytes is logged (to the standard error) before and after
+    /// each collection.
+    pub(crate) log: bool,
+}
+
+impl Heap {
+    /// Creates a new empty heap.
+    pub fn new() -> Heap {
+        Heap {
+            objects: Vec::new(),
+            strings: Table::new(),
+            gray: Vec::new(),
+            bytes_allocated: 0,
+            next_gc: INITIAL_NEXT_GC,
+            stress: false,
+            log: false,
+        }
+    }
+
+    /// Allocates a new object of the given kind and returns a reference to it.
+    ///
+    /// This function never collects garbage by itself. See `should_collect`.
+    pub fn alloc(&mut self, kind: ObjectKind) -> ObjectRef {
+        let ptr = NonNull::from(Box::leak(Box::new(Object {
+            marked: Cell::new(false),
+            kind,
+        })));
+        // SAFETY: The object will only be freed by the heap, once it is unreachable (or when the
+        // heap itself is dropped).
+        let object = unsafe { ObjectRef::from_raw(ptr) };
+        self.bytes_allocated += object.size();
+        self.objects.push(object);
+        object
+    }
+
+    /// Returns the interned string object for the given string, allocating it if needed. Every
+    /// call with an equal string returns a reference to the same object. Since every string
+    /// object is allocated through this function, strings are compared by identity.
+    pub fn intern(&mut self, string: &str) -> ObjectRef {
+        let hash = hash_string(string);
+        if let Some(object) = self.strings.find_string(string, hash) {
+            return object;
+        }
+        let object = self.alloc(ObjectKind::String(LoxString::new(string, hash)));
+        self.strings.insert(object, ());
+        object
+    }
+
+    /// Returns the interned string object for the given string, if it was already interned.
+    pub fn find_interned(&self, string: &str) -> Option<ObjectRef> {
+        self.strings.find_string(string, hash_string(string))
+    }
+
+    /// Checks if a collection should be performed before the next allocation.
+    pub fn should_collect(&self) -> bool {
+        self.stress || self.bytes_allocated > self.next_gc
+    }
+
+    /// Marks the given object as reachable.
+    pub fn mark_object(&mut self, object: ObjectRef) {
+        if object.marked.replace(true) {
+            return;
+        }
+        self.gray.push(object);
+    }
+
+    /// Marks the given value as reachable, if it is an object.
+    pub fn mark_value(&mut self, value: Value) {
+        if let Some(object) = value.as_object() {
+            self.mark_object(object);
+        }
+    }
+
+    /// Marks all the objects referenced by an object of the given kind. Besides tracing, this is
+    /// also used to keep alive the objects referenced by an object which is about to be
+    /// allocated.
+    pub fn mark_references(&mut self, kind: &ObjectKind) {
+        use ObjectKind::*;
+        match kind {
+            String(_) | Native(_) => (),
+            Function(function) => {
+                for &constant in &function.chunk.constants {
+                    self.mark_value(constant);
+                }
+            }
+            Closure(closure) => {
+                self.mark_object(closure.function);
+                for &upvalue in closure.upvalues.iter() {
+                    self.mark_object(upvalue);
+                }
+            }
+            Upvalue(upvalue) => {
+                // An open upvalue refers to a stack slot, which is already a root.
+                if let UpvalueState::Closed(value) = upvalue.state.get() {
+                    self.mark_value(value);
+                }
+            }
+            Class(class) => {
+                self.mark_object(class.name);
+                for (name, &method) in class.methods.borrow().iter() {
+                    self.mark_object(name);
+                    self.mark_object(method);
+                }
+            }
+            Instance(instance) => {
+                self.mark_object(instance.class);
+                for (name, &value) in instance.fields.borrow().iter() {
+                    self.mark_object(name);
+                    self.mark_value(value);
+                }
+            }
+            BoundMethod(bound) => {
+                self.mark_value(bound.receiver);
+                self.mark_object(bound.method);
+            }
+        }
+    }
+
+    /// Performs a collection, freeing every object which was not marked (directly or through the
+    /// marked objects). The roots must have been marked beforehand.
+    pub fn collect(&mut self) {
+        let bytes_before = self.bytes_allocated;
+        if self.log {
+            eprintln!("-- gc begin");
+        }
+
+        self.trace_references();
+        // Since the intern table holds weak references, its unmarked strings are removed before
+        // they are freed.
+        self.strings.retain(|string, _| string.marked.get());
+        self.clear_caches();
+        self.sweep();
+        self.next_gc = (self.bytes_allocated * HEAP_GROW_FACTOR).max(INITIAL_NEXT_GC);
+
+        if self.log {
+            eprintln!(
+                "-- gc end: collected {} bytes (from {} to {}), next at {}",
+                bytes_before.saturating_sub(self.bytes_allocated),
+                bytes_before,
+                self.bytes_allocated,
+                self.next_gc
+            );
+        }
+    }
+
+    /// Blackens all the gray objects, marking the objects referenced by them (which become gray).
+    /// Once there are no gray objects left, every reachable object is marked.
+    fn trace_references(&mut self) {
+        while let Some(object) = self.gray.pop() {
+            self.mark_references(&object.kind);
+        }
+    }
+
+    /// Clears the inline caches (of the marked functions) which refer to unmarked classes. Since
+    /// the caches hold weak references, this must be done before the classes are freed: a class
+    /// allocated at the same address would otherwise hit the caches.
+    fn clear_caches(&self) {
+        let functions = self
+            .objects
+            .iter()
+            .filter(|object| object.marked.get())
+            .filter_map(|object| object.as_function());
+        for function in functions {
+            for cache in &function.chunk.caches {
+                if cache.get().is_some_and(|cache| !cache.class.marked.get()) {
+                    cache.set(None);
+                }
+            }
+        }
+    }
+
+    /// Frees all the unmarked objects and unmarks the remaining ones for the next collection.
+    fn sweep(&mut self) {
+        // Since objects may grow after being allocated (such as the instances, which have their
+        // fields added later), the size of the surviving objects is recomputed.
+        self.bytes_allocated = 0;
+        for object in mem::take(&mut self.objects) {
+            if object.marked.replace(false) {
+                self.bytes_allocated += object.size();
+                self.objects.push(object);
+            } else {
+                // SAFETY: The object is unreachable, hence there are no references left to it.
+                drop(unsafe { Box::from_raw(object.as_ptr()) });
+            }
+        }
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Heap {
+        Heap::new()
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        for object in self.objects.drain(..) {
+            // SAFETY: Every object in the list was allocated by `Heap::alloc` through a `Box`,
+            // and is freed exactly once.
+            drop(unsafe { Box::from_raw(object.as_ptr()) });
+        }
+    }
+}
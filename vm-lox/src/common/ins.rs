@@ -1,15 +1,110 @@
 use std::fmt::{self, Debug};
 
-use crate::common::Value;
-
-/// Represents a single bytecode instruction.
+/// Represents a single decoded bytecode instruction, alongside with its operand.
+///
+/// In a chunk, each instruction is encoded as its `OpCode` byte followed by its operand (if any),
+/// in big-endian order. Operands that refer to constants (such as names) are indexes into the
+/// chunk's constant table. Each instruction with such an operand has a long form (e.g.
+/// `ConstantLong`), whose index is encoded over three bytes, used once the constant table outgrows
+/// the range of a single byte.
+#[derive(Copy, Clone, PartialEq, Eq)]
 pub enum Ins {
-    /// Constant value.
-    Constant(Value),
+    /// Pushes the constant with the given index.
+    Constant(u8),
+
+    /// Pushes the constant with the given (24-bit) index. Used once the constant table outgrows
+    /// the range of a single byte.
+    ConstantLong(u32),
+
+    /// Nil literal.
+    Nil,
+
+    /// True literal.
+    True,
+
+    /// False literal.
+    False,
+
+    /// Pops the top of the stack.
+    Pop,
+
+    /// Defines a global variable named after the given constant, initialized with the popped
+    /// value.
+    DefineGlobal(u8),
+
+    /// Long form of `DefineGlobal`.
+    DefineGlobalLong(u32),
+
+    /// Pushes the value of the local variable in the given stack slot.
+    GetLocal(u8),
+
+    /// Assigns the top of the stack (without popping it) to the local variable in the given stack
+    /// slot.
+    SetLocal(u8),
+
+    /// Pushes the value of the upvalue with the given index in the current closure.
+    GetUpvalue(u8),
+
+    /// Assigns the top of the stack (without popping it) to the upvalue with the given index in
+    /// the current closure.
+    SetUpvalue(u8),
+
+    /// Pushes the value of the global variable named after the given constant.
+    GetGlobal(u8),
+
+    /// Long form of `GetGlobal`.
+    GetGlobalLong(u32),
+
+    /// Assigns the top of the stack (without popping it) to the global variable named after the
+    /// given constant.
+    SetGlobal(u8),
+
+    /// Long form of `SetGlobal`.
+    SetGlobalLong(u32),
+
+    /// Pushes the value of the property (named after the given constant) of the popped instance.
+    /// If the instance has no such field, the method with such name is bound to it. The method
+    /// lookup goes through the given inline cache of the chunk.
+    GetProperty { name: u8, cache: u16 },
+
+    /// Long form of `GetProperty`.
+    GetPropertyLong { name: u32, cache: u16 },
+
+    /// Assigns the popped value to the property (named after the given constant) of the (also
+    /// popped) instance, pushing back the value.
+    SetProperty(u8),
+
+    /// Long form of `SetProperty`.
+    SetPropertyLong(u32),
+
+    /// Binds the superclass method (named after the given constant) to the current instance. The
+    /// superclass is popped and the instance is placed below it.
+    GetSuper(u8),
+
+    /// Long form of `GetSuper`.
+    GetSuperLong(u32),
+
+    /// Equality comparison.
+    Equal,
+
+    /// Greater than comparison.
+    Greater,
+
+    /// Less than comparison.
+    Less,
 
     /// Negation.
     Negate,
 
+    /// Logical not.
+    Not,
+
+    /// String representation of a value (the `show` operator).
+    Show,
+
+    /// Type name of a value (the `typeof` operator).
+    Typeof,
+
     /// Addition.
     Add,
 
@@ -22,24 +117,441 @@ pub enum Ins {
     /// Division.
     Divide,
 
-    /// Return instruction.
+    /// Unconditionally jumps forward by the given offset.
+    Jump(u16),
+
+    /// Jumps forward by the given offset if the top of the stack (which is not popped) is falsy.
+    JumpIfFalse(u16),
+
+    /// Unconditionally jumps backward by the given offset.
+    Loop(u16),
+
+    /// Creates a closure for the function in the given constant, capturing its upvalues.
+    Closure(u8),
+
+    /// Long form of `Closure`.
+    ClosureLong(u32),
+
+    /// Closes the upvalue which refers to the top of the stack, which is then popped.
+    CloseUpvalue,
+
+    /// Calls the value placed below the given number of arguments in the stack.
+    Call(u8),
+
+    /// Calls the method (named after the given constant) of the instance placed below the given
+    /// number of arguments in the stack, without binding it. A field with such name is called
+    /// instead, if any. The method lookup goes through the given inline cache of the chunk.
+    Invoke { name: u8, argc: u8, cache: u16 },
+
+    /// Long form of `Invoke`.
+    InvokeLong { name: u32, argc: u8, cache: u16 },
+
+    /// Calls the superclass method (named after the given constant) on the current instance. The
+    /// superclass is popped, and the instance is placed below the given number of arguments. The
+    /// method lookup goes through the given inline cache of the chunk.
+    SuperInvoke { name: u8, argc: u8, cache: u16 },
+
+    /// Long form of `SuperInvoke`.
+    SuperInvokeLong { name: u32, argc: u8, cache: u16 },
+
+    /// Creates a new class named after the given constant.
+    Class(u8),
+
+    /// Long form of `Class`.
+    ClassLong(u32),
+
+    /// Copies the methods of the superclass (placed below the top of the stack) into the popped
+    /// subclass.
+    Inherit,
+
+    /// Defines the popped closure as a method (named after the given constant) of the class
+    /// placed below it.
+    Method(u8),
+
+    /// Long form of `Method`.
+    MethodLong(u32),
+
+    /// Prints the popped value.
+    Print,
+
+    /// Returns the popped value from the current function.
     Return,
+
+    /// Throws the popped value as an exception, which is caught by the innermost exception
+    /// handler which covers the instruction (see `Handler`).
+    Throw,
 }
 
-impl Debug for Ins {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        const PAD: usize = 15;
+impl Ins {
+    /// Returns the operation code of the instruction.
+    pub fn opcode(&self) -> OpCode {
         use Ins::*;
         match self {
-            Constant(value) => write!(f, "{name:PAD$} {value:?}", name = "OP_CONSTANT"),
+            Constant(_) => OpCode::Constant,
+            ConstantLong(_) => OpCode::ConstantLong,
+            Nil => OpCode::Nil,
+            True => OpCode::True,
+            False => OpCode::False,
+            Pop => OpCode::Pop,
+            DefineGlobal(_) => OpCode::DefineGlobal,
+            DefineGlobalLong(_) => OpCode::DefineGlobalLong,
+            GetLocal(_) => OpCode::GetLocal,
+            SetLocal(_) => OpCode::SetLocal,
+            GetUpvalue(_) => OpCode::GetUpvalue,
+            SetUpvalue(_) => OpCode::SetUpvalue,
+            GetGlobal(_) => OpCode::GetGlobal,
+            GetGlobalLong(_) => OpCode::GetGlobalLong,
+            SetGlobal(_) => OpCode::SetGlobal,
+            SetGlobalLong(_) => OpCode::SetGlobalLong,
+            GetProperty { .. } => OpCode::GetProperty,
+            GetPropertyLong { .. } => OpCode::GetPropertyLong,
+            SetProperty(_) => OpCode::SetProperty,
+            SetPropertyLong(_) => OpCode::SetPropertyLong,
+            GetSuper(_) => OpCode::GetSuper,
+            GetSuperLong(_) => OpCode::GetSuperLong,
+            Equal => OpCode::Equal,
+            Greater => OpCode::Greater,
+            Less => OpCode::Less,
+            Negate => OpCode::Negate,
+            Not => OpCode::Not,
+            Show => OpCode::Show,
+            Typeof => OpCode::Typeof,
+            Add => OpCode::Add,
+            Subtract => OpCode::Subtract,
+            Multiply => OpCode::Multiply,
+            Divide => OpCode::Divide,
+            Jump(_) => OpCode::Jump,
+            JumpIfFalse(_) => OpCode::JumpIfFalse,
+            Loop(_) => OpCode::Loop,
+            Closure(_) => OpCode::Closure,
+            ClosureLong(_) => OpCode::ClosureLong,
+            CloseUpvalue => OpCode::CloseUpvalue,
+            Call(_) => OpCode::Call,
+            Invoke { .. } => OpCode::Invoke,
+            InvokeLong { .. } => OpCode::InvokeLong,
+            SuperInvoke { .. } => OpCode::SuperInvoke,
+            SuperInvokeLong { .. } => OpCode::SuperInvokeLong,
+            Class(_) => OpCode::Class,
+            ClassLong(_) => OpCode::ClassLong,
+            Inherit => OpCode::Inherit,
+            Method(_) => OpCode::Method,
+            MethodLong(_) => OpCode::MethodLong,
+            Print => OpCode::Print,
+            Return => OpCode::Return,
+            Throw => OpCode::Throw,
+        }
+    }
+
+    /// Returns the operand of the instruction, or zero if it has none. The instructions with many
+    /// operands have them packed in order (e.g. the name, then the cache index).
+    pub fn operand(&self) -> u64 {
+        use Ins::*;
+        match *self {
+            Constant(operand)
+            | DefineGlobal(operand)
+            | GetLocal(operand)
+            | SetLocal(operand)
+            | GetUpvalue(operand)
+            | SetUpvalue(operand)
+            | GetGlobal(operand)
+            | SetGlobal(operand)
+            | SetProperty(operand)
+            | GetSuper(operand)
+            | Closure(operand)
+            | Call(operand)
+            | Class(operand)
+            | Method(operand) => operand as u64,
+            Jump(operand) | JumpIfFalse(operand) | Loop(operand) => operand as u64,
+            ConstantLong(operand)
+            | DefineGlobalLong(operand)
+            | GetGlobalLong(operand)
+            | SetGlobalLong(operand)
+            | SetPropertyLong(operand)
+            | GetSuperLong(operand)
+            | ClosureLong(operand)
+            | ClassLong(operand)
+            | MethodLong(operand) => operand as u64,
+            GetProperty { name, cache } => (name as u64) << 16 | cache as u64,
+            GetPropertyLong { name, cache } => (name as u64) << 16 | cache as u64,
+            Invoke { name, argc, cache } | SuperInvoke { name, argc, cache } => {
+                (name as u64) << 24 | (argc as u64) << 16 | cache as u64
+            }
+            InvokeLong { name, argc, cache } | SuperInvokeLong { name, argc, cache } => {
+                (name as u64) << 24 | (argc as u64) << 16 | cache as u64
+            }
+            _ => 0,
+        }
+    }
+
+    /// Returns the index of the constant the instruction refers to (e.g. its name), if any.
+    pub fn constant_index(&self) -> Option<u32> {
+        use Ins::*;
+        match *self {
+            Constant(index) | DefineGlobal(index) | GetGlobal(index) | SetGlobal(index)
+            | SetProperty(index) | GetSuper(index) | Closure(index) | Class(index)
+            | Method(index) => Some(index as u32),
+            GetProperty { name, .. } | Invoke { name, .. } | SuperInvoke { name, .. } => {
+                Some(name as u32)
+            }
+            ConstantLong(index)
+            | DefineGlobalLong(index)
+            | GetGlobalLong(index)
+            | SetGlobalLong(index)
+            | SetPropertyLong(index)
+            | GetSuperLong(index)
+            | ClosureLong(index)
+            | ClassLong(index)
+            | MethodLong(index) => Some(index),
+            GetPropertyLong { name, .. }
+            | InvokeLong { name, .. }
+            | SuperInvokeLong { name, .. } => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Returns the effect of the instruction on the stack, as the number of values it pops and the
+    /// number of values it then pushes. Instructions which only peek at the top of the stack are
+    /// described as popping and pushing it back.
+    ///
+    /// The instructions which leave the current frame (i.e. `Return` and `Throw`) discard the
+    /// whole frame, but are only described as popping their operand.
+    pub fn stack_effect(&self) -> (usize, usize) {
+        use Ins::*;
+        match *self {
+            Constant(_) | ConstantLong(_) | Nil | True | False | GetLocal(_) | GetUpvalue(_)
+            | GetGlobal(_) | GetGlobalLong(_) | Closure(_) | ClosureLong(_) | Class(_)
+            | ClassLong(_) => (0, 1),
+            Pop | DefineGlobal(_) | DefineGlobalLong(_) | CloseUpvalue | Print | Return | Throw => {
+                (1, 0)
+            }
+            SetLocal(_) | SetUpvalue(_) | SetGlobal(_) | SetGlobalLong(_) | JumpIfFalse(_) => {
+                (1, 1)
+            }
+            GetProperty { .. } | GetPropertyLong { .. } | Negate | Not | Show | Typeof => (1, 1),
+            SetProperty(_) | SetPropertyLong(_) | GetSuper(_) | GetSuperLong(_) | Equal
+            | Greater | Less | Add | Subtract | Multiply | Divide | Inherit | Method(_)
+            | MethodLong(_) => (2, 1),
+            Jump(_) | Loop(_) => (0, 0),
+            Call(argc) | Invoke { argc, .. } | InvokeLong { argc, .. } => (argc as usize + 1, 1),
+            SuperInvoke { argc, .. } | SuperInvokeLong { argc, .. } => (argc as usize + 2, 1),
+        }
+    }
+
+    /// Returns the number of bytes of the encoded instruction.
+    pub fn len(&self) -> usize {
+        1 + self.opcode().operand_len()
+    }
+
+    /// Appends the encoded instruction to the given buffer.
+    pub fn encode(&self, code: &mut Vec<u8>) {
+        let opcode = self.opcode();
+        let operand = self.operand().to_be_bytes();
+        code.push(opcode as u8);
+        code.extend_from_slice(&operand[8 - opcode.operand_len()..]);
+    }
 
-            Negate => f.write_str("OP_NEGATE"),
-            Add => f.write_str("OP_ADD"),
-            Subtract => f.write_str("OP_NEGATE"),
-            Multiply => f.write_str("OP_MULTIPLY"),
-            Divide => f.write_str("OP_DIVIDE"),
+    /// Decodes the instruction at the start of the given code. Returns None if the code doesn't
+    /// start with a valid opcode or if its operand is truncated.
+    pub fn decode(code: &[u8]) -> Option<Ins> {
+        let opcode = OpCode::from_byte(*code.first()?)?;
+        let operand_bytes = code.get(1..1 + opcode.operand_len())?;
+        let operand = operand_bytes
+            .iter()
+            .fold(0, |operand, &byte| operand << 8 | byte as u64);
 
-            Return => f.write_str("OP_RETURN"),
+        use OpCode as Op;
+        Some(match opcode {
+            Op::Constant => Ins::Constant(operand as u8),
+            Op::ConstantLong => Ins::ConstantLong(operand as u32),
+            Op::Nil => Ins::Nil,
+            Op::True => Ins::True,
+            Op::False => Ins::False,
+            Op::Pop => Ins::Pop,
+            Op::DefineGlobal => Ins::DefineGlobal(operand as u8),
+            Op::DefineGlobalLong => Ins::DefineGlobalLong(operand as u32),
+            Op::GetLocal => Ins::GetLocal(operand as u8),
+            Op::SetLocal => Ins::SetLocal(operand as u8),
+            Op::GetUpvalue => Ins::GetUpvalue(operand as u8),
+            Op::SetUpvalue => Ins::SetUpvalue(operand as u8),
+            Op::GetGlobal => Ins::GetGlobal(operand as u8),
+            Op::GetGlobalLong => Ins::GetGlobalLong(operand as u32),
+            Op::SetGlobal => Ins::SetGlobal(operand as u8),
+            Op::SetGlobalLong => Ins::SetGlobalLong(operand as u32),
+            Op::GetProperty => Ins::GetProperty {
+                name: (operand >> 16) as u8,
+                cache: operand as u16,
+            },
+            Op::GetPropertyLong => Ins::GetPropertyLong {
+                name: (operand >> 16) as u32,
+                cache: operand as u16,
+            },
+            Op::SetProperty => Ins::SetProperty(operand as u8),
+            Op::SetPropertyLong => Ins::SetPropertyLong(operand as u32),
+            Op::GetSuper => Ins::GetSuper(operand as u8),
+            Op::GetSuperLong => Ins::GetSuperLong(operand as u32),
+            Op::Equal => Ins::Equal,
+            Op::Greater => Ins::Greater,
+            Op::Less => Ins::Less,
+            Op::Negate => Ins::Negate,
+            Op::Not => Ins::Not,
+            Op::Show => Ins::Show,
+            Op::Typeof => Ins::Typeof,
+            Op::Add => Ins::Add,
+            Op::Subtract => Ins::Subtract,
+            Op::Multiply => Ins::Multiply,
+            Op::Divide => Ins::Divide,
+            Op::Jump => Ins::Jump(operand as u16),
+            Op::JumpIfFalse => Ins::JumpIfFalse(operand as u16),
+            Op::Loop => Ins::Loop(operand as u16),
+            Op::Closure => Ins::Closure(operand as u8),
+            Op::ClosureLong => Ins::ClosureLong(operand as u32),
+            Op::CloseUpvalue => Ins::CloseUpvalue,
+            Op::Call => Ins::Call(operand as u8),
+            Op::Invoke => Ins::Invoke {
+                name: (operand >> 24) as u8,
+                argc: (operand >> 16) as u8,
+                cache: operand as u16,
+            },
+            Op::InvokeLong => Ins::InvokeLong {
+                name: (operand >> 24) as u32,
+                argc: (operand >> 16) as u8,
+                cache: operand as u16,
+            },
+            Op::SuperInvoke => Ins::SuperInvoke {
+                name: (operand >> 24) as u8,
+                argc: (operand >> 16) as u8,
+                cache: operand as u16,
+            },
+            Op::SuperInvokeLong => Ins::SuperInvokeLong {
+                name: (operand >> 24) as u32,
+                argc: (operand >> 16) as u8,
+                cache: operand as u16,
+            },
+            Op::Class => Ins::Class(operand as u8),
+            Op::ClassLong => Ins::ClassLong(operand as u32),
+            Op::Inherit => Ins::Inherit,
+            Op::Method => Ins::Method(operand as u8),
+            Op::MethodLong => Ins::MethodLong(operand as u32),
+            Op::Print => Ins::Print,
+            Op::Return => Ins::Return,
+            Op::Throw => Ins::Throw,
+        })
+    }
+}
+
+impl Debug for Ins {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const PAD: usize = 16;
+        let opcode = self.opcode();
+        match *self {
+            Ins::GetProperty { cache, .. } | Ins::GetPropertyLong { cache, .. } => {
+                let name = self.constant_index().unwrap();
+                write!(f, "{:PAD$} {name} [cache {cache}]", opcode.name())
+            }
+            Ins::Invoke { argc, cache, .. }
+            | Ins::InvokeLong { argc, cache, .. }
+            | Ins::SuperInvoke { argc, cache, .. }
+            | Ins::SuperInvokeLong { argc, cache, .. } => {
+                let name = self.constant_index().unwrap();
+                write!(
+                    f,
+                    "{:PAD$} {name} ({argc} args) [cache {cache}]",
+                    opcode.name()
+                )
+            }
+            _ if opcode.operand_len() == 0 => f.write_str(opcode.name()),
+            _ => write!(f, "{:PAD$} {}", opcode.name(), self.operand()),
         }
     }
 }
+
+macro_rules! opcodes {
+    ($($opcode:ident => $name:literal, $operand_len:literal;)*) => {
+        /// The operation code of an instruction, which is encoded as its first byte. See the
+        /// corresponding `Ins` variants for the description of each operation.
+        #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+        #[repr(u8)]
+        pub enum OpCode {
+            $($opcode,)*
+        }
+
+        impl OpCode {
+            /// Every opcode, indexed by its byte.
+            const ALL: &'static [OpCode] = &[$(OpCode::$opcode,)*];
+
+            /// Decodes the given byte. Returns None if it is not a valid opcode.
+            pub fn from_byte(byte: u8) -> Option<OpCode> {
+                OpCode::ALL.get(byte as usize).copied()
+            }
+
+            /// Returns the canonical (debug) name of the opcode.
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(OpCode::$opcode => $name,)*
+                }
+            }
+
+            /// Returns the number of bytes of the operand that follows the opcode.
+            pub fn operand_len(self) -> usize {
+                match self {
+                    $(OpCode::$opcode => $operand_len,)*
+                }
+            }
+        }
+    };
+}
+
+opcodes! {
+    Constant => "OP_CONSTANT", 1;
+    ConstantLong => "OP_CONSTANT_LONG", 3;
+    Nil => "OP_NIL", 0;
+    True => "OP_TRUE", 0;
+    False => "OP_FALSE", 0;
+    Pop => "OP_POP", 0;
+    DefineGlobal => "OP_DEFINE_GLOBAL", 1;
+    DefineGlobalLong => "OP_DEFINE_GLOBAL_LONG", 3;
+    GetLocal => "OP_GET_LOCAL", 1;
+    SetLocal => "OP_SET_LOCAL", 1;
+    GetUpvalue => "OP_GET_UPVALUE", 1;
+    SetUpvalue => "OP_SET_UPVALUE", 1;
+    GetGlobal => "OP_GET_GLOBAL", 1;
+    GetGlobalLong => "OP_GET_GLOBAL_LONG", 3;
+    SetGlobal => "OP_SET_GLOBAL", 1;
+    SetGlobalLong => "OP_SET_GLOBAL_LONG", 3;
+    GetProperty => "OP_GET_PROPERTY", 3;
+    GetPropertyLong => "OP_GET_PROPERTY_LONG", 5;
+    SetProperty => "OP_SET_PROPERTY", 1;
+    SetPropertyLong => "OP_SET_PROPERTY_LONG", 3;
+    GetSuper => "OP_GET_SUPER", 1;
+    GetSuperLong => "OP_GET_SUPER_LONG", 3;
+    Equal => "OP_EQUAL", 0;
+    Greater => "OP_GREATER", 0;
+    Less => "OP_LESS", 0;
+    Negate => "OP_NEGATE", 0;
+    Not => "OP_NOT", 0;
+    Show => "OP_SHOW", 0;
+    Typeof => "OP_TYPEOF", 0;
+    Add => "OP_ADD", 0;
+    Subtract => "OP_SUBTRACT", 0;
+    Multiply => "OP_MULTIPLY", 0;
+    Divide => "OP_DIVIDE", 0;
+    Jump => "OP_JUMP", 2;
+    JumpIfFalse => "OP_JUMP_IF_FALSE", 2;
+    Loop => "OP_LOOP", 2;
+    Closure => "OP_CLOSURE", 1;
+    ClosureLong => "OP_CLOSURE_LONG", 3;
+    CloseUpvalue => "OP_CLOSE_UPVALUE", 0;
+    Call => "OP_CALL", 1;
+    Invoke => "OP_INVOKE", 4;
+    InvokeLong => "OP_INVOKE_LONG", 6;
+    SuperInvoke => "OP_SUPER_INVOKE", 4;
+    SuperInvokeLong => "OP_SUPER_INVOKE_LONG", 6;
+    Class => "OP_CLASS", 1;
+    ClassLong => "OP_CLASS_LONG", 3;
+    Inherit => "OP_INHERIT", 0;
+    Method => "OP_METHOD", 1;
+    MethodLong => "OP_METHOD_LONG", 3;
+    Print => "OP_PRINT", 0;
+    Return => "OP_RETURN", 0;
+    Throw => "OP_THROW", 0;
+}
@@ -1,11 +1,20 @@
 mod chunk;
+mod heap;
 mod ins;
+mod object;
 mod span;
+mod table;
 mod token;
 mod value;
 
-pub use chunk::Chunk;
-pub use ins::Ins;
+pub use chunk::{CachedProperty, Chunk, Handler, InlineCache, LineRun};
+pub use heap::Heap;
+pub use ins::{Ins, OpCode};
+pub use object::{
+    BoundMethod, Capture, Class, Closure, Function, Instance, LoxString, NativeFn, NativeFunction,
+    Object, ObjectKind, ObjectRef, Upvalue, UpvalueState,
+};
 pub use span::Span;
+pub use table::Table;
 pub use token::{Token, TokenKind};
-pub use value::Value;
+pub use value::{Unpacked, Value};
@@ -0,0 +1,348 @@
+use std::{
+    cell::{Cell, RefCell},
+    fmt::{self, Debug, Display},
+    mem,
+    ops::Deref,
+    ptr::NonNull,
+};
+
+use crate::common::{Chunk, Handler, Heap, InlineCache, LineRun, Table, Value};
+
+/// Represents a heap-allocated Lox object.
+pub struct Object {
+    /// Whether the object was found to be reachable by the ongoing garbage collection.
+    pub(crate) marked: Cell<bool>,
+    pub(crate) kind: ObjectKind,
+}
+
+/// Represents the different kinds of heap-allocated Lox objects.
+pub enum ObjectKind {
+    String(LoxString),
+    Function(Function),
+    Native(NativeFunction),
+    Closure(Closure),
+    Upvalue(Upvalue),
+    Class(Class),
+    Instance(Instance),
+    BoundMethod(BoundMethod),
+}
+
+impl Object {
+    /// Returns the (approximate) number of bytes used by the object, including its owned buffers.
+    pub fn size(&self) -> usize {
+        use ObjectKind::*;
+        let owned = match &self.kind {
+            String(string) => string.len(),
+            Function(function) => {
+                function.chunk.code.capacity()
+                    + function.chunk.constants.capacity() * mem::size_of::<Value>()
+                    + function.chunk.lines.capacity() * mem::size_of::<LineRun>()
+                    + function.chunk.caches.capacity() * mem::size_of::<Option<InlineCache>>()
+                    + function.chunk.handlers.capacity() * mem::size_of::<Handler>()
+                    + function.captures.capacity() * mem::size_of::<Capture>()
+            }
+            Native(_) | Upvalue(_) | BoundMethod(_) => 0,
+            Closure(closure) => closure.upvalues.len() * mem::size_of::<ObjectRef>(),
+            Class(class) => {
+                class.methods.borrow().capacity() * mem::size_of::<(ObjectRef, ObjectRef)>()
+            }
+            Instance(instance) => {
+                instance.fields.borrow().capacity() * mem::size_of::<(ObjectRef, Value)>()
+            }
+        };
+        mem::size_of::<Object>() + owned
+    }
+
+    /// Returns the canonical type name.
+    pub fn type_name(&self) -> &'static str {
+        use ObjectKind::*;
+        match self.kind {
+            String(_) => "string",
+            Function(_) | Native(_) | Closure(_) | BoundMethod(_) => "function",
+            Upvalue(_) => "upvalue",
+            Class(_) => "class",
+            Instance(_) => "object",
+        }
+    }
+
+    /// Returns the underlying string slice if the object is a string. Otherwise None.
+    pub fn as_str(&self) -> Option<&str> {
+        self.as_string().map(|string| &*string.chars)
+    }
+
+    /// Returns the underlying string if the object is a string. Otherwise None.
+    pub fn as_string(&self) -> Option<&LoxString> {
+        match &self.kind {
+            ObjectKind::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    /// Returns the underlying function if the object is a (non-native) function. Otherwise None.
+    pub fn as_function(&self) -> Option<&Function> {
+        match &self.kind {
+            ObjectKind::Function(function) => Some(function),
+            _ => None,
+        }
+    }
+
+    /// Returns the underlying closure if the object is a closure. Otherwise None.
+    pub fn as_closure(&self) -> Option<&Closure> {
+        match &self.kind {
+            ObjectKind::Closure(closure) => Some(closure),
+            _ => None,
+        }
+    }
+
+    /// Returns the underlying upvalue if the object is an upvalue. Otherwise None.
+    pub fn as_upvalue(&self) -> Option<&Upvalue> {
+        match &self.kind {
+            ObjectKind::Upvalue(upvalue) => Some(upvalue),
+            _ => None,
+        }
+    }
+
+    /// Returns the underlying class if the object is a class. Otherwise None.
+    pub fn as_class(&self) -> Option<&Class> {
+        match &self.kind {
+            ObjectKind::Class(class) => Some(class),
+            _ => None,
+        }
+    }
+
+    /// Returns the underlying instance if the object is an instance. Otherwise None.
+    pub fn as_instance(&self) -> Option<&Instance> {
+        match &self.kind {
+            ObjectKind::Instance(instance) => Some(instance),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use ObjectKind::*;
+        match &self.kind {
+            String(string) => f.write_str(string),
+            Function(function) => write!(f, "<fun {}>", function.name()),
+            Native(native) => write!(f, "<fun (native) {}>", native.name),
+            Closure(closure) => Display::fmt(&closure.function, f),
+            Upvalue(_) => f.write_str("<upvalue>"),
+            Class(class) => write!(f, "<class {}>", class.name),
+            Instance(instance) => {
+                write!(f, "<object {} {{", instance.class.as_class().unwrap().name)?;
+                for (i, (key, val)) in instance.fields.borrow().iter().enumerate() {
+                    if i == 0 {
+                        writeln!(f)?;
+                    }
+                    writeln!(f, "  {key}: {val:?}")?;
+                }
+                f.write_str("}>")
+            }
+            BoundMethod(bound) => Display::fmt(&bound.method, f),
+        }
+    }
+}
+
+impl Debug for Object {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ObjectKind::String(string) => write!(f, "\"{}\"", &**string),
+            _ => Display::fmt(self, f),
+        }
+    }
+}
+
+/// A Lox string. Strings are always interned (see `Heap::intern`), hence their hash is computed
+/// once, when they are allocated.
+pub struct LoxString {
+    pub(crate) hash: u32,
+    chars: Box<str>,
+}
+
+impl LoxString {
+    /// Creates a new string with the given hash (see `table::hash_string`).
+    pub fn new(chars: impl Into<Box<str>>, hash: u32) -> LoxString {
+        LoxString {
+            hash,
+            chars: chars.into(),
+        }
+    }
+}
+
+impl Deref for LoxString {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.chars
+    }
+}
+
+/// A compiled Lox function, which owns its chunk of bytecode.
+pub struct Function {
+    pub(crate) arity: u8,
+    /// Describes how each of the function's upvalues is captured once a closure is created.
+    pub(crate) captures: Vec<Capture>,
+    pub(crate) chunk: Chunk,
+}
+
+impl Function {
+    /// Creates a new function.
+    pub fn new(arity: u8, captures: Vec<Capture>, chunk: Chunk) -> Function {
+        Function {
+            arity,
+            captures,
+            chunk,
+        }
+    }
+
+    /// Returns the function name, which is also the name of its chunk.
+    pub fn name(&self) -> &str {
+        self.chunk.name()
+    }
+}
+
+/// Describes from where a closure captures one of its upvalues.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Capture {
+    /// If true, the captured variable is a local variable of the enclosing function, in the
+    /// given stack slot. Otherwise, it is one of the upvalues of the enclosing function, with the
+    /// given index.
+    pub(crate) is_local: bool,
+    pub(crate) index: u8,
+}
+
+/// A function alongside the variables it captured from the enclosing scopes.
+pub struct Closure {
+    pub(crate) function: ObjectRef,
+    pub(crate) upvalues: Box<[ObjectRef]>,
+}
+
+/// A variable captured by a closure.
+///
+/// While the variable is still alive in the stack, the upvalue is *open* and refers to its stack
+/// slot. Once the variable goes out of scope, the upvalue is *closed*, taking its value along.
+pub struct Upvalue {
+    pub(crate) state: Cell<UpvalueState>,
+}
+
+#[derive(Copy, Clone)]
+pub enum UpvalueState {
+    Open(usize),
+    Closed(Value),
+}
+
+/// A Lox class.
+pub struct Class {
+    pub(crate) name: ObjectRef,
+    /// The class methods (closures), keyed by their interned names. Since methods are copied down
+    /// from the superclass when the class is created, inherited methods are also included.
+    pub(crate) methods: RefCell<Table<ObjectRef>>,
+}
+
+impl Class {
+    /// Creates a new class with no methods.
+    pub fn new(name: ObjectRef) -> Class {
+        Class {
+            name,
+            methods: RefCell::new(Table::new()),
+        }
+    }
+}
+
+/// An instance of a Lox class.
+pub struct Instance {
+    pub(crate) class: ObjectRef,
+    /// The instance fields, keyed by their interned names.
+    pub(crate) fields: RefCell<Table<Value>>,
+}
+
+impl Instance {
+    /// Creates a new instance of the given class with no fields.
+    pub fn new(class: ObjectRef) -> Instance {
+        Instance {
+            class,
+            fields: RefCell::new(Table::new()),
+        }
+    }
+}
+
+/// A method (closure) bound to the instance on which it was accessed.
+pub struct BoundMethod {
+    pub(crate) receiver: Value,
+    pub(crate) method: ObjectRef,
+}
+
+/// The Rust functions (or closures) that implement native Lox functions. They may allocate the
+/// objects they return (e.g. strings) in the given heap, which never collects garbage by itself.
+/// In case of error, the returned message is reported as a runtime error.
+pub type NativeFn = Box<dyn Fn(&mut Heap, &[Value]) -> Result<Value, String>>;
+
+/// A Lox function implemented in Rust.
+pub struct NativeFunction {
+    pub(crate) name: Box<str>,
+    pub(crate) arity: u8,
+    pub(crate) function: NativeFn,
+}
+
+/// A reference to an object allocated by the `Heap`.
+///
+/// The referenced object is owned by the heap, which is responsible for freeing it. Hence an
+/// `ObjectRef` must never be dereferenced after the heap that allocated it has been dropped, nor
+/// after the object has been collected. The garbage collector only keeps alive the objects which
+/// are reachable from the roots, so every reference held outside of the heap must be marked as a
+/// root during a collection.
+#[derive(Copy, Clone)]
+pub struct ObjectRef(NonNull<Object>);
+
+impl ObjectRef {
+    /// Creates a new reference from the given object pointer.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must be valid for as long as the returned reference (or any copy of it) is
+    /// used. This is ensured by the `Heap`, which should be the only caller of this function.
+    pub(crate) unsafe fn from_raw(ptr: NonNull<Object>) -> ObjectRef {
+        ObjectRef(ptr)
+    }
+
+    /// Returns the underlying object pointer.
+    pub(crate) fn as_ptr(self) -> *mut Object {
+        self.0.as_ptr()
+    }
+
+    /// Checks if both references point to the same object.
+    pub fn ptr_eq(self, other: ObjectRef) -> bool {
+        self.0 == other.0
+    }
+}
+
+/// Object references are compared by identity.
+impl PartialEq for ObjectRef {
+    fn eq(&self, other: &ObjectRef) -> bool {
+        self.ptr_eq(*other)
+    }
+}
+
+impl Eq for ObjectRef {}
+
+impl Deref for ObjectRef {
+    type Target = Object;
+
+    fn deref(&self) -> &Object {
+        // SAFETY: The heap keeps the object alive while it is reachable by the program.
+        unsafe { self.0.as_ref() }
+    }
+}
+
+impl Display for ObjectRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Display::fmt(&**self, f)
+    }
+}
+
+impl Debug for ObjectRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Debug::fmt(&**self, f)
+    }
+}
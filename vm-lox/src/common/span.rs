@@ -29,7 +29,7 @@ impl Span {
     }
 
     /// Returns the span `Range<usize>` representation.
-    pub fn into_range(&self) -> Range<usize> {
+    pub fn range(&self) -> Range<usize> {
         Range {
             start: self.lo,
             end: self.hi,
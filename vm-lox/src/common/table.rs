@@ -0,0 +1,380 @@
+use std::mem;
+
+use crate::common::ObjectRef;
+
+/// The minimum (non-zero) capacity of a table.
+const CAPACITY_MIN: usize = 8;
+
+/// A table grows once this fraction of its entries are in use (including the tombstones).
+const MAX_LOAD: (usize, usize) = (3, 4);
+
+/// A hash table keyed by interned strings, such as the globals table or the fields of an
+/// instance.
+///
+/// The table uses open addressing with linear probing: each key is stored in the first free entry
+/// at or after the index given by its hash. Removed entries are replaced by tombstones, so that the
+/// probe sequences which go through them are not broken. Since the keys are interned, they are
+/// compared by identity, while their hash is the one cached by the string object.
+#[derive(Clone)]
+pub struct Table<V> {
+    /// The entries, whose number is either zero or a power of two.
+    entries: Box<[Entry<V>]>,
+    /// The number of entries which are occupied or tombstones.
+    used: usize,
+    /// The number of occupied entries.
+    len: usize,
+}
+
+#[derive(Copy, Clone)]
+enum Entry<V> {
+    Empty,
+    Tombstone,
+    Occupied(ObjectRef, V),
+}
+
+impl<V> Table<V> {
+    /// Creates a new empty table. No memory is allocated until the first insertion.
+    pub fn new() -> Table<V> {
+        Table {
+            entries: Box::new([]),
+            used: 0,
+            len: 0,
+        }
+    }
+
+    /// Returns the number of entries (free or not) of the table.
+    pub fn capacity(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns a reference to the value of the given key, if any.
+    pub fn get(&self, key: ObjectRef) -> Option<&V> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        match &self.entries[self.find(key)] {
+            Entry::Occupied(_, value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Returns the index of the entry which holds the given key, if any. The index may be used
+    /// by later lookups of the same key (see `get_at`), until the table grows.
+    pub fn index_of(&self, key: ObjectRef) -> Option<usize> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        let index = self.find(key);
+        matches!(self.entries[index], Entry::Occupied(..)).then_some(index)
+    }
+
+    /// Returns a reference to the value of the entry at the given index, if it holds the given
+    /// key. Unlike `get`, this doesn't probe the table.
+    pub fn get_at(&self, index: usize, key: ObjectRef) -> Option<&V> {
+        match self.entries.get(index) {
+            Some(Entry::Occupied(other, value)) if *other == key => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Returns a mutable reference to the value of the given key, if any.
+    pub fn get_mut(&mut self, key: ObjectRef) -> Option<&mut V> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        match &mut self.entries[self.find(key)] {
+            Entry::Occupied(_, value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Sets the value of the given key. Returns true if the key is new.
+    pub fn insert(&mut self, key: ObjectRef, value: V) -> bool {
+        if (self.used + 1) * MAX_LOAD.1 > self.capacity() * MAX_LOAD.0 {
+            self.grow();
+        }
+        let index = self.find(key);
+        let is_new = match self.entries[index] {
+            Entry::Empty => {
+                self.used += 1;
+                true
+            }
+            // The tombstone was already counted as used.
+            Entry::Tombstone => true,
+            Entry::Occupied(..) => false,
+        };
+        if is_new {
+            self.len += 1;
+        }
+        self.entries[index] = Entry::Occupied(key, value);
+        is_new
+    }
+
+    /// Removes the given key. Returns true if it was in the table.
+    pub fn remove(&mut self, key: ObjectRef) -> bool {
+        if self.entries.is_empty() {
+            return false;
+        }
+        let index = self.find(key);
+        if !matches!(self.entries[index], Entry::Occupied(..)) {
+            return false;
+        }
+        self.entries[index] = Entry::Tombstone;
+        self.len -= 1;
+        true
+    }
+
+    /// Removes every key for which the given predicate returns false.
+    pub fn retain(&mut self, mut f: impl FnMut(ObjectRef, &V) -> bool) {
+        for entry in self.entries.iter_mut() {
+            if let Entry::Occupied(key, value) = entry {
+                if !f(*key, value) {
+                    *entry = Entry::Tombstone;
+                    self.len -= 1;
+                }
+            }
+        }
+    }
+
+    /// Returns an iterator over the keys and values of the table, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (ObjectRef, &V)> {
+        self.entries.iter().filter_map(|entry| match entry {
+            Entry::Occupied(key, value) => Some((*key, value)),
+            _ => None,
+        })
+    }
+
+    /// Returns the key which holds the given string (whose hash is given), if any. Unlike the
+    /// other lookups, the keys are compared by content, hence this is used to intern strings.
+    pub fn find_string(&self, string: &str, hash: u32) -> Option<ObjectRef> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        let mask = self.capacity() - 1;
+        let mut index = hash as usize & mask;
+        loop {
+            match &self.entries[index] {
+                Entry::Empty => return None,
+                Entry::Tombstone => (),
+                Entry::Occupied(key, _) => {
+                    let key_string = key.as_string().unwrap();
+                    if key_string.hash == hash && **key_string == *string {
+                        return Some(*key);
+                    }
+                }
+            }
+            index = (index + 1) & mask;
+        }
+    }
+
+    /// Returns the index of the entry which holds the given key or, if there is none, the index
+    /// of the entry the key should be inserted into (reusing the first tombstone found, if any).
+    /// The table must have at least one entry.
+    fn find(&self, key: ObjectRef) -> usize {
+        let mask = self.capacity() - 1;
+        let mut index = hash_key(key) as usize & mask;
+        let mut tombstone = None;
+        // Since the load factor is lower than one, there is always an empty entry to stop at.
+        loop {
+            match &self.entries[index] {
+                Entry::Empty => return tombstone.unwrap_or(index),
+                Entry::Tombstone => {
+                    tombstone.get_or_insert(index);
+                }
+                Entry::Occupied(other, _) if *other == key => return index,
+                Entry::Occupied(..) => (),
+            }
+            index = (index + 1) & mask;
+        }
+    }
+
+    /// Doubles the capacity of the table, reinserting its keys and dropping its tombstones. If
+    /// most entries are tombstones, the capacity is kept instead, so that a table whose keys are
+    /// repeatedly inserted and removed doesn't grow indefinitely.
+    fn grow(&mut self) {
+        let capacity = if self.len * 2 < self.capacity() {
+            self.capacity()
+        } else {
+            (self.capacity() * 2).max(CAPACITY_MIN)
+        };
+        let entries = (0..capacity).map(|_| Entry::Empty).collect();
+        let old_entries = mem::replace(&mut self.entries, entries);
+        self.used = self.len;
+        for entry in old_entries.into_vec() {
+            if let Entry::Occupied(key, value) = entry {
+                let index = self.find(key);
+                self.entries[index] = Entry::Occupied(key, value);
+            }
+        }
+    }
+}
+
+impl<V> Default for Table<V> {
+    fn default() -> Table<V> {
+        Table::new()
+    }
+}
+
+/// Returns the cached hash of the given key, which must be a string.
+fn hash_key(key: ObjectRef) -> u32 {
+    key.as_string().expect("Table keys must be strings").hash
+}
+
+/// Hashes the given string (with the 32-bit FNV-1a hash function).
+pub fn hash_string(string: &str) -> u32 {
+    let mut hash: u32 = 2166136261;
+    for &byte in string.as_bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(16777619);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Heap;
+
+    /// Returns the given number of keys which have the same index in a table of the minimum
+    /// capacity, hence are probed in insertion order.
+    fn colliding_keys(heap: &mut Heap, count: usize) -> Vec<ObjectRef> {
+        let mask = CAPACITY_MIN as u32 - 1;
+        let home = hash_string("key0") & mask;
+        (0..)
+            .map(|i| format!("key{i}"))
+            .filter(|key| hash_string(key) & mask == home)
+            .take(count)
+            .map(|key| heap.intern(&key))
+            .collect()
+    }
+
+    fn tombstones<V>(table: &Table<V>) -> usize {
+        table
+            .entries
+            .iter()
+            .filter(|entry| matches!(entry, Entry::Tombstone))
+            .count()
+    }
+
+    #[test]
+    fn reuses_tombstones() {
+        let mut heap = Heap::new();
+        let keys = colliding_keys(&mut heap, 4);
+        let mut table = Table::new();
+        for (i, &key) in keys[..3].iter().enumerate() {
+            assert!(table.insert(key, i));
+        }
+        assert!(table.remove(keys[1]));
+        assert!(!table.remove(keys[1]));
+        assert_eq!((table.len, table.used, tombstones(&table)), (2, 3, 1));
+        // The key after the tombstone is still found.
+        assert_eq!(table.get(keys[2]), Some(&2));
+
+        // A new key takes the place of the tombstone, without using another entry.
+        assert!(table.insert(keys[3], 3));
+        assert_eq!((table.len, table.used, tombstones(&table)), (3, 3, 0));
+        assert!(table.remove(keys[3]));
+        assert!(table.insert(keys[1], 10));
+        assert_eq!((table.len, table.used, tombstones(&table)), (3, 3, 0));
+        assert_eq!(table.get(keys[1]), Some(&10));
+        assert_eq!(table.get(keys[3]), None);
+        assert!(!table.insert(keys[1], 11));
+        assert_eq!(table.get(keys[1]), Some(&11));
+    }
+
+    #[test]
+    fn finds_strings_past_tombstones() {
+        let mut heap = Heap::new();
+        let keys = colliding_keys(&mut heap, 4);
+        let mut table = Table::new();
+        for &key in &keys[..3] {
+            table.insert(key, ());
+        }
+        table.remove(keys[0]);
+        table.remove(keys[1]);
+        for (i, key) in keys.iter().enumerate() {
+            let string = key.as_str().unwrap();
+            let found = table.find_string(string, hash_string(string));
+            assert_eq!(
+                found.map(|found| found.ptr_eq(*key)),
+                (i == 2).then_some(true)
+            );
+        }
+        assert_eq!(table.find_string("missing", hash_string("missing")), None);
+    }
+
+    #[test]
+    fn grows_and_rehashes() {
+        let mut heap = Heap::new();
+        let keys: Vec<_> = (0..100).map(|i| heap.intern(&format!("k{i}"))).collect();
+        let mut table = Table::new();
+        assert_eq!(table.capacity(), 0);
+        for (i, &key) in keys.iter().enumerate() {
+            table.insert(key, i);
+            let capacity = table.capacity();
+            assert!(capacity.is_power_of_two() && capacity >= CAPACITY_MIN);
+            assert!(table.used * MAX_LOAD.1 <= capacity * MAX_LOAD.0);
+        }
+        assert_eq!(table.capacity(), 256);
+        for (i, &key) in keys.iter().enumerate() {
+            assert_eq!(table.get(key), Some(&i));
+        }
+
+        // Growing drops the tombstones.
+        for &key in &keys[..50] {
+            table.remove(key);
+        }
+        let mut inserted = 0;
+        while tombstones(&table) > 0 {
+            table.insert(heap.intern(&format!("extra{inserted}")), 0);
+            inserted += 1;
+        }
+        assert_eq!(table.used, table.len);
+        assert_eq!(table.len, 50 + inserted);
+        for (i, &key) in keys.iter().enumerate() {
+            assert_eq!(table.get(key), (i >= 50).then_some(&i));
+        }
+    }
+
+    #[test]
+    fn keeps_the_capacity_when_most_entries_are_tombstones() {
+        let mut heap = Heap::new();
+        let keys: Vec<_> = (0..100).map(|i| heap.intern(&format!("k{i}"))).collect();
+        let mut table = Table::new();
+        table.insert(keys[0], 0);
+        let mut rehashes = 0;
+        for (i, &key) in keys.iter().enumerate().skip(1) {
+            let rehashed = (table.used + 1) * MAX_LOAD.1 > table.capacity() * MAX_LOAD.0;
+            table.insert(key, i);
+            if rehashed {
+                // The tombstones were dropped, leaving the two keys.
+                assert_eq!((table.used, tombstones(&table)), (2, 0));
+                rehashes += 1;
+            }
+            table.remove(key);
+            assert_eq!(table.capacity(), CAPACITY_MIN);
+        }
+        assert!(rehashes > 0);
+        assert_eq!(table.len, 1);
+        assert_eq!(table.get(keys[0]), Some(&0));
+    }
+
+    #[test]
+    fn retained_lookups_probe_past_removed_keys() {
+        let mut heap = Heap::new();
+        let keys = colliding_keys(&mut heap, 3);
+        let mut table = Table::new();
+        for (i, &key) in keys.iter().enumerate() {
+            table.insert(key, i);
+        }
+        table.retain(|_, &value| value != 0);
+        assert_eq!((table.len, tombstones(&table)), (2, 1));
+        assert_eq!(table.get(keys[0]), None);
+        assert_eq!(table.get(keys[2]), Some(&2));
+        let string = keys[2].as_str().unwrap();
+        assert!(table.find_string(string, hash_string(string)).is_some());
+        let mut remaining: Vec<_> = table.iter().map(|(_, &value)| value).collect();
+        remaining.sort();
+        assert_eq!(remaining, [1, 2]);
+    }
+}
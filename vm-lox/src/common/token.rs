@@ -6,6 +6,16 @@ pub struct Token {
     pub(crate) span: Span,
 }
 
+impl Token {
+    /// Creates a new dummy token, used as a placeholder by the compiler.
+    pub fn dummy() -> Self {
+        Token {
+            kind: TokenKind::Dummy,
+            span: Span::new(0, 0),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum TokenKind {
     Identifier(String),
@@ -50,6 +60,9 @@ pub enum TokenKind {
     Print,
     Typeof,
     Show,
+    Try,
+    Catch,
+    Throw,
 
     Eof,
 
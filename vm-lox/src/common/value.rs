@@ -1,21 +1,136 @@
-use std::fmt::{self, Display};
+use std::fmt::{self, Debug, Display};
 
-/// Represents a Lox value.
-#[derive(Debug, Clone)]
-pub enum Value {
+use crate::common::ObjectRef;
+
+#[cfg(feature = "nan-boxing")]
+mod nan_boxed;
+#[cfg(not(feature = "nan-boxing"))]
+mod tagged;
+
+#[cfg(feature = "nan-boxing")]
+pub use nan_boxed::Value;
+#[cfg(not(feature = "nan-boxing"))]
+pub use tagged::Value;
+
+// A Lox value (`Value`) has two alternative representations:
+//
+// - By default, it is a tagged union (see `tagged`), i.e. an enum.
+// - With the `nan-boxing` feature, it is a single `u64` (see `nan_boxed`), which holds either a
+//   double or a quiet NaN whose payload encodes the other kinds of values.
+//
+// Both representations are opaque. Values are created through the `From` implementations (and
+// `Value::NIL`), and inspected through the accessors below or by matching on `Value::unpack`.
+// Each representation only provides `NIL`, `pack` and `unpack`.
+
+/// The unpacked contents of a value, which may be pattern matched.
+#[derive(Copy, Clone)]
+pub enum Unpacked {
+    Nil,
+    Bool(bool),
     Number(f64),
+    Object(ObjectRef),
+}
+
+impl Value {
+    /// Returns the canonical type name.
+    pub fn type_name(&self) -> &'static str {
+        match self.unpack() {
+            Unpacked::Nil => "nil",
+            Unpacked::Bool(_) => "boolean",
+            Unpacked::Number(_) => "number",
+            Unpacked::Object(object) => object.type_name(),
+        }
+    }
+
+    /// Checks if the value is falsy.
+    ///   * Truthy lox values: all numbers (incl. 0), all strings (incl. "") and `true`.
+    ///   * Falsy lox values: `false` and `nil`.
+    pub fn is_falsy(&self) -> bool {
+        matches!(self.unpack(), Unpacked::Nil | Unpacked::Bool(false))
+    }
+
+    /// Returns the underlying number if the value is a number. Otherwise None.
+    pub fn as_number(&self) -> Option<f64> {
+        match self.unpack() {
+            Unpacked::Number(number) => Some(number),
+            _ => None,
+        }
+    }
+
+    /// Returns the underlying object reference if the value is an object. Otherwise None.
+    pub fn as_object(&self) -> Option<ObjectRef> {
+        match self.unpack() {
+            Unpacked::Object(object) => Some(object),
+            _ => None,
+        }
+    }
+
+    /// Returns the underlying string slice if the value is a string object. Otherwise None.
+    pub fn as_str(&self) -> Option<&str> {
+        match self.unpack() {
+            // SAFETY: As in `ObjectRef::deref`, the object is kept alive by the heap while it is
+            // reachable (e.g. through this value).
+            Unpacked::Object(object) => unsafe { &*object.as_ptr() }.as_str(),
+            _ => None,
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(boolean: bool) -> Value {
+        Value::pack(Unpacked::Bool(boolean))
+    }
+}
+
+impl From<f64> for Value {
+    fn from(number: f64) -> Value {
+        Value::pack(Unpacked::Number(number))
+    }
+}
+
+impl From<ObjectRef> for Value {
+    fn from(object: ObjectRef) -> Value {
+        Value::pack(Unpacked::Object(object))
+    }
+}
+
+/// Checks if two values are equal. No type coercion is performed so both types must be equal.
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        use Unpacked::*;
+        match (self.unpack(), other.unpack()) {
+            (Nil, Nil) => true,
+            (Bool(a), Bool(b)) => a == b,
+            (Number(a), Number(b)) => a == b,
+            // Since strings are interned, equal strings are the same object.
+            (Object(a), Object(b)) => a.ptr_eq(b),
+            _ => false,
+        }
+    }
 }
 
 impl Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Value::Number(number) => {
-                if number.floor() == *number {
+        match self.unpack() {
+            Unpacked::Nil => f.write_str("nil"),
+            Unpacked::Bool(boolean) => Display::fmt(&boolean, f),
+            Unpacked::Number(number) => {
+                if number.floor() == number {
                     write!(f, "{:.0}", number)
                 } else {
                     write!(f, "{}", number)
                 }
             }
+            Unpacked::Object(object) => Display::fmt(&object, f),
+        }
+    }
+}
+
+impl Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.unpack() {
+            Unpacked::Object(object) => Debug::fmt(&object, f),
+            _ => Display::fmt(self, f),
         }
     }
 }
@@ -0,0 +1,144 @@
+use std::ptr::NonNull;
+
+use crate::common::{value::Unpacked, Object, ObjectRef};
+
+#[cfg(not(target_pointer_width = "64"))]
+compile_error!("The `nan-boxing` feature requires 64-bit pointers");
+
+/// The bits which are set in every quiet NaN, plus one extra bit, so that the NaN produced by
+/// invalid arithmetic operations (the "Intel FP Indefinite" value) is never mistaken for a boxed
+/// value.
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+
+/// The sign bit, which is set in the boxed object pointers.
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+
+const TAG_NIL: u64 = 1;
+const TAG_FALSE: u64 = 2;
+const TAG_TRUE: u64 = 3;
+
+/// Represents a Lox value, as a NaN-boxed `u64`.
+///
+/// Numbers are stored as their plain bits. The other kinds of values are stored in the payload
+/// of a quiet NaN: nil and the booleans as small tags, and objects as their pointer (which must
+/// fit in the lower 48 bits) with the sign bit set. Numbers which are themselves NaNs are
+/// canonicalized, so that they never overlap with the boxed values.
+#[derive(Copy, Clone)]
+pub struct Value(u64);
+
+impl Value {
+    /// The nil value.
+    pub const NIL: Value = Value(QNAN | TAG_NIL);
+
+    /// Creates a value with the given contents.
+    #[inline]
+    pub fn pack(unpacked: Unpacked) -> Value {
+        match unpacked {
+            Unpacked::Nil => Value::NIL,
+            Unpacked::Bool(false) => Value(QNAN | TAG_FALSE),
+            Unpacked::Bool(true) => Value(QNAN | TAG_TRUE),
+            Unpacked::Number(number) if number.is_nan() => Value(f64::NAN.to_bits()),
+            Unpacked::Number(number) => Value(number.to_bits()),
+            Unpacked::Object(object) => {
+                let ptr = object.as_ptr() as u64;
+                // The user-space addresses fit in 47 bits on x86-64 and 48 bits on AArch64, unless
+                // the allocator asks for larger ones (such as the 57-bit addresses of x86-64 with
+                // 5-level paging). Since the value would otherwise be silently corrupted, this is
+                // checked in release builds too (the branch is never taken, hence well predicted).
+                assert_eq!(ptr & (SIGN_BIT | QNAN), 0, "Object pointer exceeds 48 bits");
+                Value(SIGN_BIT | QNAN | ptr)
+            }
+        }
+    }
+
+    /// Returns the contents of the value.
+    #[inline]
+    pub fn unpack(self) -> Unpacked {
+        if self.0 & QNAN != QNAN {
+            Unpacked::Number(f64::from_bits(self.0))
+        } else if self.0 & SIGN_BIT != 0 {
+            let ptr = (self.0 & !(SIGN_BIT | QNAN)) as *mut Object;
+            // SAFETY: The pointer was boxed by `pack`, from a valid (hence non-null) reference.
+            Unpacked::Object(unsafe { ObjectRef::from_raw(NonNull::new_unchecked(ptr)) })
+        } else {
+            match self.0 & !QNAN {
+                TAG_NIL => Unpacked::Nil,
+                TAG_FALSE => Unpacked::Bool(false),
+                TAG_TRUE => Unpacked::Bool(true),
+                _ => unreachable!("Invalid NaN-boxed value {:#018x}", self.0),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Heap;
+
+    #[test]
+    fn canonicalizes_nans() {
+        let nans = [
+            f64::NAN,
+            -f64::NAN,
+            f64::INFINITY - f64::INFINITY,
+            // NaNs whose payload looks like a boxed value.
+            f64::from_bits(QNAN | TAG_NIL),
+            f64::from_bits(QNAN | TAG_TRUE),
+            f64::from_bits(SIGN_BIT | QNAN | 0x1000),
+        ];
+        for nan in nans {
+            let value = Value::from(nan);
+            assert_eq!(value.0, f64::NAN.to_bits());
+            assert!(matches!(value.unpack(), Unpacked::Number(number) if number.is_nan()));
+            assert!(value != value);
+        }
+    }
+
+    #[test]
+    fn round_trips_numbers() {
+        for number in [
+            0.0,
+            1.5,
+            -3.0,
+            f64::MAX,
+            f64::MIN_POSITIVE,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+        ] {
+            assert_eq!(Value::from(number).0, number.to_bits());
+            assert!(matches!(Value::from(number).unpack(), Unpacked::Number(n) if n == number));
+        }
+        let Unpacked::Number(zero) = Value::from(-0.0).unpack() else {
+            panic!("-0 should unpack as a number");
+        };
+        assert!(zero.is_sign_negative());
+        assert!(Value::from(-0.0) == Value::from(0.0));
+    }
+
+    #[test]
+    fn round_trips_nil_and_booleans() {
+        assert!(matches!(Value::NIL.unpack(), Unpacked::Nil));
+        assert!(matches!(Value::from(false).unpack(), Unpacked::Bool(false)));
+        assert!(matches!(Value::from(true).unpack(), Unpacked::Bool(true)));
+        let bits = [Value::NIL.0, Value::from(false).0, Value::from(true).0];
+        assert!(bits.iter().all(|&bits| f64::from_bits(bits).is_nan()));
+        assert!(bits.iter().all(|&bits| bits != f64::NAN.to_bits()));
+        assert!(Value::NIL != Value::from(false));
+    }
+
+    #[test]
+    fn round_trips_objects() {
+        let mut heap = Heap::new();
+        let objects = [heap.intern("a"), heap.intern("b")];
+        for object in objects {
+            let value = Value::from(object);
+            assert_eq!(value.0 & (SIGN_BIT | QNAN), SIGN_BIT | QNAN);
+            assert!(
+                matches!(value.unpack(), Unpacked::Object(unpacked) if unpacked.ptr_eq(object))
+            );
+        }
+        assert_eq!(Value::from(objects[0]).as_str(), Some("a"));
+        assert!(Value::from(objects[0]) != Value::from(objects[1]));
+    }
+}
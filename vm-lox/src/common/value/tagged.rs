@@ -0,0 +1,22 @@
+use crate::common::value::Unpacked;
+
+/// Represents a Lox value, as a tagged union.
+#[derive(Copy, Clone)]
+pub struct Value(Unpacked);
+
+impl Value {
+    /// The nil value.
+    pub const NIL: Value = Value(Unpacked::Nil);
+
+    /// Creates a value with the given contents.
+    #[inline]
+    pub fn pack(unpacked: Unpacked) -> Value {
+        Value(unpacked)
+    }
+
+    /// Returns the contents of the value.
+    #[inline]
+    pub fn unpack(self) -> Unpacked {
+        self.0
+    }
+}
@@ -0,0 +1,1474 @@
+use std::mem;
+
+use crate::{
+    common::{
+        Capture, Chunk, Function, Handler, Ins, ObjectKind, ObjectRef, Span, Table, Token,
+        TokenKind, Value,
+    },
+    optimizer,
+    pipeline::{Diagnostic, Error, Result},
+    scanner::Scanner,
+    vm::Vm,
+};
+
+/// Compiles the given source string into the chunk of bytecode of the top-level script. Objects
+/// referenced by the chunk (such as string literals and nested functions) are allocated in the
+/// heap of the given virtual machine, which may collect garbage meanwhile.
+pub fn compile(source: &str, vm: &mut Vm) -> Result<Chunk> {
+    Compiler::new(source, vm).compile()
+}
+
+/// Compiles the given REPL input, as `compile` does. Unlike a script, an expression statement
+/// which is ended by the end of the input (instead of a semicolon) prints its value.
+pub fn compile_repl(source: &str, vm: &mut Vm) -> Result<Chunk> {
+    let mut compiler = Compiler::new(source, vm);
+    compiler.repl_mode = true;
+    compiler.compile()
+}
+
+/// The single-pass compiler. Parses the tokens produced by the scanner and directly emits the
+/// corresponding bytecode instructions, without any intermediate representation.
+pub struct Compiler<'s, 'v> {
+    scanner: Scanner<'s>,
+    vm: &'v mut Vm,
+    /// The objects allocated (or interned) during the compilation. Since they are referenced by
+    /// the chunks being compiled, they are the compiler's garbage collection roots.
+    objects: Vec<ObjectRef>,
+    current_token: Token,
+    prev_token: Token,
+    /// The stack of functions being compiled. The innermost function is the last one.
+    functions: Vec<FunctionState>,
+    /// The stack of classes being compiled. The innermost class is the last one.
+    classes: Vec<ClassState>,
+    line_starts: Vec<usize>,
+    /// The start of the left operand of the infix expression being compiled, read by the infix
+    /// parse functions before they compile their right operand (see `parse_precedence`).
+    infix_lo: usize,
+    diagnostics: Vec<Diagnostic>,
+    panic_mode: bool,
+    /// Whether the source is a REPL input (see `compile_repl`).
+    repl_mode: bool,
+}
+
+// The compiler implementation.
+//
+// # Grammar:
+//
+// -----------------------------------------------------------------------------
+//
+// program       ::= decl* EOF ;
+//
+// decl          ::= var_decl
+//                 | class_decl
+//                 | fun_decl
+//                 | stmt ;
+//
+// var_decl      ::= "var" IDENTIFIER ( "=" expr )? ";" ;
+// class_decl    ::= "class" IDENTIFIER ( "<" IDENTIFIER )? "{" fn* "}" ;
+// fun_decl      ::= "fun" fn ;
+//
+// fn            ::= IDENTIFIER "(" params? ")" block_stmt ;
+// params        ::= IDENTIFIER ( "," IDENTIFIER )* ;
+//
+// stmt          ::= if_stmt
+//                 | for_stmt
+//                 | while_stmt
+//                 | return_stmt
+//                 | print_stmt
+//                 | try_stmt
+//                 | throw_stmt
+//                 | block_stmt
+//                 | expr_stmt ;
+//
+// if_stmt       ::= "if" "(" expr ")" stmt ( "else" stmt )? ;
+// for_stmt      ::= "for"
+//                   "(" ( var_decl | expr_stmt | ";" ) expr? ";" expr? ")"
+//                   stmt ;
+// while_stmt    ::= "while" "(" expr ")" stmt ;
+// return_stmt   ::= "return" expr? ";" ;
+// print_stmt    ::= "print" expr ";" ;
+// try_stmt      ::= "try" block_stmt "catch" "(" IDENTIFIER ")" block_stmt ;
+// throw_stmt    ::= "throw" expr ";" ;
+// block_stmt    ::= "{" decl* "}" ;
+// expr_stmt     ::= expr ";" ;
+//
+// -----------------------------------------------------------------------------
+//
+// Expressions are parsed using a Pratt parser. Each token kind may be associated with a prefix
+// and an infix parse function, alongside with the precedence of the infix operator. See the
+// `ParseRule::of` function.
+impl Compiler<'_, '_> {
+    fn compile(mut self) -> Result<Chunk> {
+        self.advance(); // The first advancement.
+        while !self.is_at_end() {
+            self.decl();
+        }
+        self.emit_return();
+        let script = self.end_function();
+
+        if self.diagnostics.is_empty() {
+            Ok(script.chunk)
+        } else {
+            Err(Error::CompileError(self.diagnostics))
+        }
+    }
+
+    //
+    // Declarations
+    //
+
+    fn decl(&mut self) {
+        match self.current_token.kind {
+            TokenKind::Var => self.var_decl(),
+            TokenKind::Class => self.class_decl(),
+            TokenKind::Fun => self.fun_decl(),
+            _ => self.stmt(),
+        }
+
+        if self.panic_mode {
+            self.synchronize();
+        }
+    }
+
+    fn var_decl(&mut self) {
+        self.advance(); // Consumes the `var`.
+        let name = self.consume_ident("Expected variable name");
+        self.declare_variable(&name);
+
+        if self.take(TokenKind::Equal) {
+            self.expr();
+        } else {
+            self.emit(Ins::Nil);
+        }
+        self.consume(
+            TokenKind::Semicolon,
+            "Expected `;` after variable declaration",
+        );
+
+        self.define_variable(&name);
+    }
+
+    fn class_decl(&mut self) {
+        self.advance(); // Consumes the `class`.
+        let name = self.consume_ident("Expected class name");
+        let class_name = self.identifier_constant(&name.name);
+        self.declare_variable(&name);
+        self.emit(constant_ins(class_name, Ins::Class, Ins::ClassLong));
+        self.define_variable(&name);
+        self.classes.push(ClassState {
+            has_superclass: false,
+        });
+
+        if self.take(TokenKind::Less) {
+            let super_name = self.consume_ident("Expected superclass name");
+            if super_name.name == name.name {
+                self.error_at(super_name.span, "Class can't inherit itself");
+            }
+            self.named_variable(&super_name, false);
+
+            // The superclass is stored in a local variable named `super` (which can't be referred
+            // to by user code as a regular variable) so that it can be captured by the methods.
+            self.begin_scope();
+            self.add_local("super");
+            self.mark_initialized();
+
+            self.named_variable(&name, false);
+            self.emit(Ins::Inherit);
+            self.classes.last_mut().unwrap().has_superclass = true;
+        }
+
+        // The class is placed in the stack so that the methods can be bound to it.
+        self.named_variable(&name, false);
+        self.consume(TokenKind::LeftBrace, "Expected `{` before class body");
+        while !self.is(TokenKind::RightBrace) && !self.is_at_end() {
+            self.method();
+        }
+        self.consume(TokenKind::RightBrace, "Expected `}` after class body");
+        self.emit(Ins::Pop);
+
+        if self.classes.pop().unwrap().has_superclass {
+            self.end_scope();
+        }
+    }
+
+    fn method(&mut self) {
+        let name = self.consume_ident("Expected method name");
+        let kind = if name.name == "init" {
+            FunctionKind::Initializer
+        } else {
+            FunctionKind::Method
+        };
+        self.function(&name, kind);
+        let name = self.identifier_constant(&name.name);
+        self.emit(constant_ins(name, Ins::Method, Ins::MethodLong));
+    }
+
+    fn fun_decl(&mut self) {
+        self.advance(); // Consumes the `fun`.
+        let name = self.consume_ident("Expected function name");
+        self.declare_variable(&name);
+        // Unlike other variables, a function may refer to itself (recursion) within its own body,
+        // hence it is marked as initialized before its body is compiled.
+        self.mark_initialized();
+        self.function(&name, FunctionKind::Function);
+        self.define_variable(&name);
+    }
+
+    /// Compiles the parameters and the body of a function, emitting the creation of its closure.
+    fn function(&mut self, name: &Ident, kind: FunctionKind) {
+        self.functions.push(FunctionState::new(kind, &name.name));
+        self.begin_scope();
+
+        self.consume(TokenKind::LeftParen, "Expected `(` after function name");
+        if !self.is(TokenKind::RightParen) {
+            loop {
+                if self.current().arity == u8::MAX {
+                    self.error_at_current("Function can't have more than 255 parameters");
+                }
+                self.current_mut().arity = self.current().arity.saturating_add(1);
+                let param = self.consume_ident("Expected parameter name");
+                self.declare_variable(&param);
+                self.define_variable(&param);
+                if !self.take(TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(
+            TokenKind::RightParen,
+            "Expected `)` after function parameter list",
+        );
+        self.block();
+
+        // Since the function's frame is discarded as a whole when it returns, there is no need to
+        // end its outermost scope.
+        self.emit_return();
+        let FunctionState {
+            arity,
+            chunk,
+            upvalues,
+            ..
+        } = self.end_function();
+        let function = self.alloc(ObjectKind::Function(Function::new(arity, upvalues, chunk)));
+        let function = self.make_constant(Value::from(function));
+        self.emit(constant_ins(function, Ins::Closure, Ins::ClosureLong));
+    }
+
+    /// Declares a new local variable (in case of a local scope) with the given name. The variable
+    /// remains uninitialized until `define_variable` is called.
+    fn declare_variable(&mut self, name: &Ident) {
+        // Global variables are late bound, hence they are not declared.
+        let current = self.current();
+        if current.scope_depth == 0 {
+            return;
+        }
+
+        let shadows_in_scope = current
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth == current.scope_depth))
+            .any(|local| local.name == name.name);
+        if shadows_in_scope {
+            self.error_at(name.span, "Can't shadow a identifier in the same scope");
+        }
+
+        if self.current().locals.len() == LOCALS_MAX {
+            self.error_at(name.span, "Too many local variables in function");
+            return;
+        }
+        self.add_local(&name.name);
+    }
+
+    /// Adds a new (uninitialized) local variable to the current scope.
+    fn add_local(&mut self, name: &str) {
+        self.current_mut().locals.push(Local {
+            name: name.into(),
+            depth: None,
+            is_captured: false,
+        });
+    }
+
+    /// Defines a variable, which becomes available for use. A global variable is stored in the
+    /// globals table, while a local variable (already in the stack) is simply marked as
+    /// initialized.
+    fn define_variable(&mut self, name: &Ident) {
+        if self.current().scope_depth == 0 {
+            let name = self.identifier_constant(&name.name);
+            self.emit(constant_ins(name, Ins::DefineGlobal, Ins::DefineGlobalLong));
+        } else {
+            self.mark_initialized();
+        }
+    }
+
+    /// Marks the last declared local variable as initialized.
+    fn mark_initialized(&mut self) {
+        let current = self.current_mut();
+        if current.scope_depth == 0 {
+            return;
+        }
+        if let Some(local) = current.locals.last_mut() {
+            local.depth = Some(current.scope_depth);
+        }
+    }
+
+    //
+    // Statements
+    //
+
+    fn stmt(&mut self) {
+        match self.current_token.kind {
+            TokenKind::If => self.if_stmt(),
+            TokenKind::For => self.for_stmt(),
+            TokenKind::While => self.while_stmt(),
+            TokenKind::Return => self.return_stmt(),
+            TokenKind::Print => self.print_stmt(),
+            TokenKind::Try => self.try_stmt(),
+            TokenKind::Throw => self.throw_stmt(),
+            TokenKind::LeftBrace => {
+                self.begin_scope();
+                self.block();
+                self.end_scope();
+            }
+            _ => self.expr_stmt(),
+        }
+    }
+
+    fn if_stmt(&mut self) {
+        self.advance(); // Consumes the `if`.
+        self.consume(
+            TokenKind::LeftParen,
+            "Expected `if` condition group opening",
+        );
+        self.expr();
+        self.consume(
+            TokenKind::RightParen,
+            "Expected `if` condition group to be closed",
+        );
+
+        // The condition is kept in the stack by the conditional jump, hence it must be popped at
+        // the start of both branches.
+        let then_jump = self.emit_jump(Ins::JumpIfFalse);
+        self.emit(Ins::Pop);
+        self.stmt();
+        let else_jump = self.emit_jump(Ins::Jump);
+
+        self.patch_jump(then_jump);
+        self.emit(Ins::Pop);
+        if self.take(TokenKind::Else) {
+            self.stmt();
+        }
+        self.patch_jump(else_jump);
+    }
+
+    // Like in tree-lox, `for` statements are compiled as if they were the equivalent `while`
+    // statement. E.g.:
+    //
+    // ```
+    // for (var i = 1; i <= 10; i = i + 1) { print show i; }
+    // ```
+    //
+    // Is compiled as:
+    //
+    // ```
+    // {
+    //    var i = 1;
+    //    while (i <= 10) {
+    //      { print show i; }
+    //      i = i + 1;
+    //    }
+    // }
+    // ```
+    //
+    // Since the increment clause textually precedes the body, it is compiled before it. Hence the
+    // body jumps back to the increment, which then jumps back to the condition.
+    fn for_stmt(&mut self) {
+        self.advance(); // Consumes the `for`.
+        self.begin_scope();
+        self.consume(TokenKind::LeftParen, "Expected `for` clauses group opening");
+
+        match self.current_token.kind {
+            TokenKind::Semicolon => self.advance(),
+            TokenKind::Var => self.var_decl(),
+            _ => self.expr_stmt(),
+        }
+
+        let mut loop_start = self.current().chunk.code.len();
+        let exit_jump = if self.take(TokenKind::Semicolon) {
+            // If there is no condition in the for clauses, the loop is infinite.
+            None
+        } else {
+            self.expr();
+            self.consume(TokenKind::Semicolon, "Expected `;` after `for` condition");
+            let exit_jump = self.emit_jump(Ins::JumpIfFalse);
+            self.emit(Ins::Pop);
+            Some(exit_jump)
+        };
+
+        if !self.is(TokenKind::RightParen) {
+            let body_jump = self.emit_jump(Ins::Jump);
+            let incr_start = self.current().chunk.code.len();
+            self.expr();
+            self.emit(Ins::Pop);
+            self.emit_loop(loop_start);
+            loop_start = incr_start;
+            self.patch_jump(body_jump);
+        }
+        self.consume(
+            TokenKind::RightParen,
+            "Expected `for` clauses group to be closed",
+        );
+
+        self.stmt();
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit(Ins::Pop);
+        }
+        self.end_scope();
+    }
+
+    fn while_stmt(&mut self) {
+        self.advance(); // Consumes the `while`.
+        let loop_start = self.current().chunk.code.len();
+        self.consume(
+            TokenKind::LeftParen,
+            "Expected `while` condition group opening",
+        );
+        self.expr();
+        self.consume(
+            TokenKind::RightParen,
+            "Expected `while` condition group to be closed",
+        );
+
+        let exit_jump = self.emit_jump(Ins::JumpIfFalse);
+        self.emit(Ins::Pop);
+        self.stmt();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit(Ins::Pop);
+    }
+
+    fn return_stmt(&mut self) {
+        self.advance(); // Consumes the `return`.
+        if self.current().kind == FunctionKind::Script {
+            self.error_at_prev("Illegal return statement");
+        }
+
+        if self.take(TokenKind::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.current().kind == FunctionKind::Initializer {
+                self.error_at_prev("Can't return value from class initializer");
+            }
+            self.expr();
+            self.consume(TokenKind::Semicolon, "Expected `;` after return");
+            self.emit(Ins::Return);
+        }
+    }
+
+    fn print_stmt(&mut self) {
+        self.advance(); // Consumes the `print`.
+        self.expr();
+        self.consume(TokenKind::Semicolon, "Expected `;` after value");
+        self.emit(Ins::Print);
+    }
+
+    // A `try` statement is compiled as its `try` block, followed by a jump over its `catch` block.
+    // The range of the `try` block is registered as an exception handler of the chunk (see
+    // `Handler`), whose target is the `catch` block. Since the VM unwinds the stack to the height
+    // it had before the `try` block and then pushes the exception, the exception lands in the
+    // slot of the `catch` variable.
+    fn try_stmt(&mut self) {
+        self.advance(); // Consumes the `try`.
+        let depth = self.current().locals.len();
+        let start = self.current().chunk.code.len();
+        self.begin_scope();
+        self.block();
+        self.end_scope();
+        let end = self.current().chunk.code.len();
+        let exit_jump = self.emit_jump(Ins::Jump);
+
+        let target = self.current().chunk.code.len();
+        self.consume(TokenKind::Catch, "Expected `catch` after `try` block");
+        self.consume(
+            TokenKind::LeftParen,
+            "Expected `catch` variable group opening",
+        );
+        let name = self.consume_ident("Expected `catch` variable name");
+        self.consume(
+            TokenKind::RightParen,
+            "Expected `catch` variable group to be closed",
+        );
+        self.begin_scope();
+        self.declare_variable(&name);
+        self.mark_initialized();
+        self.block();
+        self.end_scope();
+        self.patch_jump(exit_jump);
+
+        // Registered once the `catch` block is compiled, so that the handlers of the nested `try`
+        // statements precede this one.
+        self.current_mut().chunk.handlers.push(Handler {
+            start: start as u32,
+            end: end as u32,
+            target: target as u32,
+            depth: depth as u16,
+        });
+    }
+
+    fn throw_stmt(&mut self) {
+        self.advance(); // Consumes the `throw`.
+        let lo = self.prev_token.span.lo;
+        self.expr();
+        self.consume(TokenKind::Semicolon, "Expected `;` after thrown value");
+        self.emit_at(Ins::Throw, self.span_from(lo));
+    }
+
+    fn block(&mut self) {
+        self.consume(TokenKind::LeftBrace, "Expected block to be opened");
+        while !self.is(TokenKind::RightBrace) && !self.is_at_end() {
+            self.decl();
+        }
+        self.consume(TokenKind::RightBrace, "Expected block to be closed");
+    }
+
+    fn expr_stmt(&mut self) {
+        self.expr();
+        // In the REPL, the value of a trailing expression is shown.
+        if self.repl_mode && self.is_at_end() {
+            self.emit(Ins::Print);
+            return;
+        }
+        self.consume(TokenKind::Semicolon, "Expected `;` after expression");
+        self.emit(Ins::Pop);
+    }
+
+    //
+    // Expressions
+    //
+
+    fn expr(&mut self) {
+        self.parse_precedence(Precedence::Assignment);
+    }
+
+    /// Parses any expression whose precedence is at least as high as the given one.
+    fn parse_precedence(&mut self, precedence: Precedence) {
+        let lo = self.current_token.span.lo;
+        self.advance();
+        let prefix = match ParseRule::of(&self.prev_token.kind).prefix {
+            Some(prefix) => prefix,
+            None => {
+                self.error_at_prev("Expected any expression");
+                return;
+            }
+        };
+        // Only low precedence expressions may be used as assignment targets. Otherwise, an
+        // expression such as `a + b = c` would be compiled as `a + (b = c)`.
+        let can_assign = precedence <= Precedence::Assignment;
+        prefix(self, can_assign);
+
+        while precedence <= ParseRule::of(&self.current_token.kind).precedence {
+            self.advance();
+            // Every token kind with a precedence other than `None` has an infix parse function.
+            let infix = ParseRule::of(&self.prev_token.kind).infix.unwrap();
+            self.infix_lo = lo;
+            infix(self, can_assign);
+        }
+
+        // If the `=` was not consumed by any of the parse functions, the target is invalid.
+        if can_assign && self.is(TokenKind::Equal) {
+            self.error_at_current("Invalid assignment target");
+        }
+    }
+
+    fn variable(&mut self, can_assign: bool) {
+        let name = self.prev_ident();
+        self.named_variable(&name, can_assign);
+    }
+
+    /// Compiles an access to (or an assignment of) the variable with the given name.
+    fn named_variable(&mut self, name: &Ident, can_assign: bool) {
+        let level = self.functions.len() - 1;
+        let (get, set) = if let Some(slot) = self.resolve_local(level, name) {
+            (Ins::GetLocal(slot), Ins::SetLocal(slot))
+        } else if let Some(index) = self.resolve_upvalue(level, name) {
+            (Ins::GetUpvalue(index), Ins::SetUpvalue(index))
+        } else {
+            let name = self.identifier_constant(&name.name);
+            (
+                constant_ins(name, Ins::GetGlobal, Ins::GetGlobalLong),
+                constant_ins(name, Ins::SetGlobal, Ins::SetGlobalLong),
+            )
+        };
+
+        if can_assign && self.take(TokenKind::Equal) {
+            self.expr();
+            self.emit_at(set, self.span_from(name.span.lo));
+        } else {
+            self.emit_at(get, name.span);
+        }
+    }
+
+    /// Returns the stack slot of the local variable with the given name, in the function at the
+    /// given level of the function stack. If there is no such local variable, returns None.
+    fn resolve_local(&mut self, level: usize, name: &Ident) -> Option<u8> {
+        let (slot, local) = self.functions[level]
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name == name.name)?;
+        if local.depth.is_none() {
+            self.error_at(
+                name.span,
+                "Can't read local variable in its own initializer",
+            );
+        }
+        Some(slot as u8)
+    }
+
+    /// Returns the index of the upvalue which captures the variable with the given name, in the
+    /// function at the given level of the function stack. The variable is looked up in the
+    /// enclosing functions, from the innermost to the outermost one. If it can't be found, it is
+    /// assumed to be a global one, hence None is returned.
+    fn resolve_upvalue(&mut self, level: usize, name: &Ident) -> Option<u8> {
+        if level == 0 {
+            return None;
+        }
+        if let Some(slot) = self.resolve_local(level - 1, name) {
+            self.functions[level - 1].locals[slot as usize].is_captured = true;
+            return Some(self.add_upvalue(level, name, true, slot));
+        }
+        // Each intermediate function also captures the variable, so that it is available to the
+        // innermost one once its closure is created.
+        let index = self.resolve_upvalue(level - 1, name)?;
+        Some(self.add_upvalue(level, name, false, index))
+    }
+
+    /// Adds an upvalue to the function at the given level of the function stack, returning its
+    /// index. A variable is only captured once by each function.
+    fn add_upvalue(&mut self, level: usize, name: &Ident, is_local: bool, index: u8) -> u8 {
+        let capture = Capture { is_local, index };
+        let upvalues = &mut self.functions[level].upvalues;
+        if let Some(existing) = upvalues.iter().position(|&other| other == capture) {
+            return existing as u8;
+        }
+        if upvalues.len() == UPVALUES_MAX {
+            self.error_at(name.span, "Too many closure variables in function");
+            return 0;
+        }
+        upvalues.push(capture);
+        (upvalues.len() - 1) as u8
+    }
+
+    fn this(&mut self, _: bool) {
+        if self.classes.is_empty() {
+            self.error_at_prev("Illegal this expression, can't use this outside of a class");
+            return;
+        }
+        let this = Ident {
+            name: "this".into(),
+            span: self.prev_token.span,
+        };
+        self.named_variable(&this, false);
+    }
+
+    fn super_(&mut self, _: bool) {
+        match self.classes.last() {
+            None => {
+                self.error_at_prev("Illegal super expression, can't use super outside of a class")
+            }
+            Some(class) if !class.has_superclass => self.error_at_prev(
+                "Illegal super expression, can't use super within a class with no superclass",
+            ),
+            Some(_) => (),
+        }
+        let span = self.prev_token.span;
+        let lo = span.lo;
+        self.consume(TokenKind::Dot, "Expected `.` after `super`");
+        let method = self.consume_ident("Expected superclass method name");
+        let method = self.identifier_constant(&method.name);
+
+        // Binding the superclass method requires both the instance and the superclass.
+        let this = Ident {
+            name: "this".into(),
+            span,
+        };
+        let super_ = Ident {
+            name: "super".into(),
+            span,
+        };
+        self.named_variable(&this, false);
+        if self.take(TokenKind::LeftParen) {
+            let argc = self.arguments();
+            self.named_variable(&super_, false);
+            let cache = self.make_cache();
+            self.emit_at(
+                constant_ins(
+                    method,
+                    |name| Ins::SuperInvoke { name, argc, cache },
+                    |name| Ins::SuperInvokeLong { name, argc, cache },
+                ),
+                self.span_from(lo),
+            );
+        } else {
+            self.named_variable(&super_, false);
+            self.emit_at(
+                constant_ins(method, Ins::GetSuper, Ins::GetSuperLong),
+                self.span_from(lo),
+            );
+        }
+    }
+
+    fn number(&mut self, _: bool) {
+        let TokenKind::Number(number) = self.prev_token.kind else {
+            unreachable!("Compiler bug. Expected number token");
+        };
+        self.emit_constant(Value::from(number));
+    }
+
+    fn string(&mut self, _: bool) {
+        let TokenKind::String(string) = &self.prev_token.kind else {
+            unreachable!("Compiler bug. Expected string token");
+        };
+        let string = self.intern(&string.clone());
+        self.emit_constant(Value::from(string));
+    }
+
+    fn literal(&mut self, _: bool) {
+        match self.prev_token.kind {
+            TokenKind::Nil => self.emit(Ins::Nil),
+            TokenKind::True => self.emit(Ins::True),
+            TokenKind::False => self.emit(Ins::False),
+            _ => unreachable!("Compiler bug. Expected literal token"),
+        }
+    }
+
+    fn grouping(&mut self, _: bool) {
+        self.expr();
+        self.consume(TokenKind::RightParen, "Expected group to be closed");
+    }
+
+    fn unary(&mut self, _: bool) {
+        let operator = self.prev_token.clone();
+
+        // Compiles the operand.
+        self.parse_precedence(Precedence::Unary);
+
+        use TokenKind::*;
+        let ins = match operator.kind {
+            Minus => Ins::Negate,
+            Bang => Ins::Not,
+            Show => Ins::Show,
+            Typeof => Ins::Typeof,
+            _ => unreachable!("Compiler bug. Expected unary operator token"),
+        };
+        self.emit_at(ins, self.span_from(operator.span.lo));
+    }
+
+    fn and(&mut self, _: bool) {
+        // If the left operand is falsy, it is the result of the whole expression, hence the right
+        // operand is skipped. Otherwise the left operand is discarded.
+        let end_jump = self.emit_jump(Ins::JumpIfFalse);
+        self.emit(Ins::Pop);
+        self.parse_precedence(Precedence::And);
+        self.patch_jump(end_jump);
+    }
+
+    fn or(&mut self, _: bool) {
+        // If the left operand is truthy, it is the result of the whole expression, hence the right
+        // operand is skipped. Otherwise the left operand is discarded.
+        let else_jump = self.emit_jump(Ins::JumpIfFalse);
+        let end_jump = self.emit_jump(Ins::Jump);
+        self.patch_jump(else_jump);
+        self.emit(Ins::Pop);
+        self.parse_precedence(Precedence::Or);
+        self.patch_jump(end_jump);
+    }
+
+    fn call(&mut self, _: bool) {
+        let lo = self.infix_lo;
+        let argc = self.arguments();
+        self.emit_at(Ins::Call(argc), self.span_from(lo));
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        let lo = self.infix_lo;
+        let name = self.consume_ident("Expect property name after `.`");
+        let name = self.identifier_constant(&name.name);
+        let ins = if can_assign && self.take(TokenKind::Equal) {
+            self.expr();
+            constant_ins(name, Ins::SetProperty, Ins::SetPropertyLong)
+        } else if self.take(TokenKind::LeftParen) {
+            // A method call is performed at once, without binding the method.
+            let argc = self.arguments();
+            let cache = self.make_cache();
+            constant_ins(
+                name,
+                |name| Ins::Invoke { name, argc, cache },
+                |name| Ins::InvokeLong { name, argc, cache },
+            )
+        } else {
+            let cache = self.make_cache();
+            constant_ins(
+                name,
+                |name| Ins::GetProperty { name, cache },
+                |name| Ins::GetPropertyLong { name, cache },
+            )
+        };
+        self.emit_at(ins, self.span_from(lo));
+    }
+
+    /// Compiles the arguments of a call, returning their count.
+    fn arguments(&mut self) -> u8 {
+        let mut argc: u8 = 0;
+        if !self.is(TokenKind::RightParen) {
+            loop {
+                self.expr();
+                if argc == u8::MAX {
+                    self.error_at_prev("Call can't have more than 255 arguments");
+                }
+                argc = argc.saturating_add(1);
+                if !self.take(TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenKind::RightParen, "Expected `)` to close call syntax");
+        argc
+    }
+
+    fn binary(&mut self, _: bool) {
+        let lo = self.infix_lo;
+        let operator = self.prev_token.clone();
+
+        // Binary operators are left associative, hence the right operand must bind tighter.
+        let rule = ParseRule::of(&operator.kind);
+        self.parse_precedence(rule.precedence.next());
+
+        use TokenKind::*;
+        let span = self.span_from(lo);
+        let (ins, negated) = match operator.kind {
+            Plus => (Ins::Add, false),
+            Minus => (Ins::Subtract, false),
+            Star => (Ins::Multiply, false),
+            Slash => (Ins::Divide, false),
+            EqualEqual => (Ins::Equal, false),
+            BangEqual => (Ins::Equal, true),
+            Greater => (Ins::Greater, false),
+            GreaterEqual => (Ins::Less, true),
+            Less => (Ins::Less, false),
+            LessEqual => (Ins::Greater, true),
+            _ => unreachable!("Compiler bug. Expected binary operator token"),
+        };
+        self.emit_at(ins, span);
+        if negated {
+            self.emit_at(Ins::Not, span);
+        }
+    }
+}
+
+// The compiler helper methods.
+impl<'s, 'v> Compiler<'s, 'v> {
+    /// Creates a new compiler.
+    pub fn new(source: &'s str, vm: &'v mut Vm) -> Compiler<'s, 'v> {
+        let line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Compiler {
+            scanner: Scanner::new(source),
+            vm,
+            objects: Vec::new(),
+            current_token: Token::dummy(),
+            prev_token: Token::dummy(),
+            functions: vec![FunctionState::new(FunctionKind::Script, "<script>")],
+            classes: Vec::new(),
+            line_starts,
+            infix_lo: 0,
+            diagnostics: Vec::new(),
+            panic_mode: false,
+            repl_mode: false,
+        }
+    }
+
+    /// Advances the compiler, reporting and skipping all the `Error` tokens. Once the scanner is
+    /// exhausted, the `Eof` token is indefinitely repeated.
+    fn advance(&mut self) {
+        let next = loop {
+            let Some(next) = self.scanner.next() else {
+                break self.current_token.clone();
+            };
+            if let TokenKind::Error(message) = &next.kind {
+                let message = message.clone();
+                // Only an unterminated token (i.e. a string) may extend to the end of the source.
+                let unexpected_end = self.scanner.is_exhausted();
+                self.report(next.span, message, unexpected_end);
+                continue;
+            }
+            break next;
+        };
+        self.prev_token = mem::replace(&mut self.current_token, next);
+    }
+
+    /// Checks if the current token matches the kind of the given one.
+    fn is(&self, expected: TokenKind) -> bool {
+        self.current_token.kind == expected
+    }
+
+    /// Checks if the current token matches the kind of the given one. In such case advances and
+    /// returns true. Otherwise returns false.
+    fn take(&mut self, expected: TokenKind) -> bool {
+        let res = self.is(expected);
+        if res {
+            self.advance();
+        }
+        res
+    }
+
+    /// Checks if the current token matches the kind of the given one. In such case advances.
+    /// Otherwise reports an error with the provided message.
+    fn consume(&mut self, expected: TokenKind, msg: &str) {
+        if !self.take(expected) {
+            self.error_at_current(msg);
+        }
+    }
+
+    /// Checks if the current token is an identifier. In such case advances and returns it.
+    /// Otherwise reports an error with the provided message.
+    fn consume_ident(&mut self, msg: &str) -> Ident {
+        if let TokenKind::Identifier(_) = self.current_token.kind {
+            self.advance();
+            self.prev_ident()
+        } else {
+            self.error_at_current(msg);
+            Ident {
+                name: String::new(),
+                span: self.current_token.span,
+            }
+        }
+    }
+
+    /// Returns the previous token as an identifier. The previous token must be an identifier.
+    fn prev_ident(&self) -> Ident {
+        let TokenKind::Identifier(name) = &self.prev_token.kind else {
+            unreachable!("Compiler bug. Expected identifier token");
+        };
+        Ident {
+            name: name.clone(),
+            span: self.prev_token.span,
+        }
+    }
+
+    /// Returns the state of the innermost function being compiled.
+    fn current(&self) -> &FunctionState {
+        self.functions.last().unwrap()
+    }
+
+    /// Returns the (mutable) state of the innermost function being compiled.
+    fn current_mut(&mut self) -> &mut FunctionState {
+        self.functions.last_mut().unwrap()
+    }
+
+    fn begin_scope(&mut self) {
+        self.current_mut().scope_depth += 1;
+    }
+
+    /// Ends the current scope, discarding all of its local variables. The variables captured by
+    /// closures are moved to the heap.
+    fn end_scope(&mut self) {
+        self.current_mut().scope_depth -= 1;
+        loop {
+            let current = self.current_mut();
+            match current.locals.last() {
+                Some(local) if local.depth.is_none_or(|depth| depth > current.scope_depth) => {
+                    let local = current.locals.pop().unwrap();
+                    if local.is_captured {
+                        self.emit(Ins::CloseUpvalue);
+                    } else {
+                        self.emit(Ins::Pop);
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    /// Checks if the compiler has finished.
+    fn is_at_end(&self) -> bool {
+        self.is(TokenKind::Eof)
+    }
+
+    /// Synchronizes the compiler state with the current token, exiting the panic mode.
+    ///
+    /// The synchronization process discards all tokens until it reaches a statement boundary:
+    ///   * If the previous token is a semicolon, the compiler is *probably* starting a new
+    ///     statement.
+    ///   * If the next token marks the start of a new statement.
+    fn synchronize(&mut self) {
+        use TokenKind::*;
+        self.panic_mode = false;
+        while !self.is_at_end() {
+            if self.prev_token.kind == Semicolon {
+                return;
+            }
+            match self.current_token.kind {
+                Class | For | Fun | If | Print | Return | Var | While | Try | Throw => return,
+                _ => self.advance(),
+            }
+        }
+    }
+
+    /// Allocates a new object of the given kind in the VM heap.
+    fn alloc(&mut self, kind: ObjectKind) -> ObjectRef {
+        self.mark_roots();
+        let object = self.vm.alloc(kind);
+        self.objects.push(object);
+        object
+    }
+
+    /// Returns the interned string object for the given string.
+    fn intern(&mut self, string: &str) -> ObjectRef {
+        self.mark_roots();
+        let object = self.vm.intern(string);
+        self.objects.push(object);
+        object
+    }
+
+    /// Adds the given value to the constant table of the chunk being compiled, returning its
+    /// index. Reports an error if the index doesn't fit in a long constant operand.
+    fn make_constant(&mut self, value: Value) -> u32 {
+        let index = self.current_mut().chunk.add_constant(value);
+        if index > CONSTANTS_MAX {
+            self.error_at_prev("Too many constants in one chunk");
+            return 0;
+        }
+        index as u32
+    }
+
+    /// Adds an inline cache to the chunk being compiled, returning its index. Reports an error if
+    /// the index doesn't fit in the cache operand.
+    fn make_cache(&mut self) -> u16 {
+        let index = self.current_mut().chunk.add_cache();
+        u16::try_from(index).unwrap_or_else(|_| {
+            self.error_at_prev("Too many property accesses in one chunk");
+            0
+        })
+    }
+
+    /// Returns the index of the constant which holds the (interned) string of the given name. Each
+    /// name is only added once to the constant table.
+    fn identifier_constant(&mut self, name: &str) -> u32 {
+        let name = self.intern(name);
+        if let Some(&index) = self.current().names.get(name) {
+            return index;
+        }
+        let index = self.make_constant(Value::from(name));
+        self.current_mut().names.insert(name, index);
+        index
+    }
+
+    /// Writes an instruction which pushes the given constant value.
+    fn emit_constant(&mut self, value: Value) {
+        let index = self.make_constant(value);
+        self.emit(constant_ins(index, Ins::Constant, Ins::ConstantLong));
+    }
+
+    /// Marks the compiler roots if the VM is about to collect garbage.
+    fn mark_roots(&mut self) {
+        if self.vm.heap.should_collect() {
+            for &object in &self.objects {
+                self.vm.heap.mark_object(object);
+            }
+        }
+    }
+
+    /// Writes the given instruction to the chunk being compiled, attributing it to the previous
+    /// token.
+    fn emit(&mut self, ins: Ins) {
+        self.emit_at(ins, self.prev_token.span);
+    }
+
+    /// Writes the given instruction to the chunk being compiled, attributing it to the given span
+    /// (such as the one of the expression it evaluates). Its line is the one in which the span
+    /// starts.
+    fn emit_at(&mut self, ins: Ins, span: Span) {
+        let line = self.line_of(span);
+        self.current_mut().chunk.write(ins, line, span);
+    }
+
+    /// Returns the span from the given source index to the end of the previous token.
+    fn span_from(&self, lo: usize) -> Span {
+        Span::new(lo, self.prev_token.span.hi)
+    }
+
+    /// Finishes the compilation of the innermost function, optimizing its chunk (if enabled).
+    fn end_function(&mut self) -> FunctionState {
+        let mut function = self.functions.pop().unwrap();
+        if self.vm.optimize {
+            optimizer::optimize(&mut function.chunk);
+        }
+        function
+    }
+
+    /// Writes the instructions of an implicit return, which returns nil (or the instance, in the
+    /// case of an initializer).
+    fn emit_return(&mut self) {
+        if self.current().kind == FunctionKind::Initializer {
+            self.emit_many([Ins::GetLocal(0), Ins::Return]);
+        } else {
+            self.emit_many([Ins::Nil, Ins::Return]);
+        }
+    }
+
+    /// Writes the given kind of jump instruction with a placeholder offset, returning its offset
+    /// in the chunk so that it can be later patched by `patch_jump`.
+    fn emit_jump(&mut self, jump: fn(u16) -> Ins) -> usize {
+        let offset = self.current().chunk.code.len();
+        self.emit(jump(u16::MAX));
+        offset
+    }
+
+    /// Patches the jump instruction at the given offset so that it jumps to the end of the chunk
+    /// (i.e. where the next instruction will be written).
+    fn patch_jump(&mut self, jump_offset: usize) {
+        let code = &mut self.current_mut().chunk.code;
+        debug_assert!(
+            matches!(
+                Ins::decode(&code[jump_offset..]),
+                Some(Ins::Jump(_) | Ins::JumpIfFalse(_))
+            ),
+            "Compiler bug. Expected jump instruction"
+        );
+        // The offset is relative to the instruction that follows the jump.
+        let operand_start = jump_offset + 1;
+        let Ok(offset) = u16::try_from(code.len() - (operand_start + 2)) else {
+            self.error_at_prev("Too much code to jump over");
+            return;
+        };
+        code[operand_start..operand_start + 2].copy_from_slice(&offset.to_be_bytes());
+    }
+
+    /// Writes a loop instruction which jumps back to the given offset.
+    fn emit_loop(&mut self, loop_start: usize) {
+        // The offset is relative to the instruction that follows the loop, hence it must also
+        // account for the loop instruction itself.
+        let loop_len = Ins::Loop(0).len();
+        let Ok(offset) = u16::try_from(self.current().chunk.code.len() - loop_start + loop_len)
+        else {
+            self.error_at_prev("Loop body is too large");
+            return;
+        };
+        self.emit(Ins::Loop(offset));
+    }
+
+    /// Writes all the given instructions to the chunk being compiled.
+    fn emit_many<const N: usize>(&mut self, instructions: [Ins; N]) {
+        for ins in instructions {
+            self.emit(ins);
+        }
+    }
+
+    /// Returns the (one-based) line number in which the given span starts.
+    fn line_of(&self, span: Span) -> u32 {
+        self.line_starts.partition_point(|&start| start <= span.lo) as u32
+    }
+
+    fn error_at_current(&mut self, message: impl Into<String>) {
+        let unexpected_end = self.current_token.kind == TokenKind::Eof;
+        self.report(self.current_token.span, message, unexpected_end);
+    }
+
+    fn error_at_prev(&mut self, message: impl Into<String>) {
+        // The end of the source may be consumed by a parse function which expected some token
+        // (e.g. the prefix of an expression).
+        let unexpected_end = self.prev_token.kind == TokenKind::Eof;
+        self.report(self.prev_token.span, message, unexpected_end);
+    }
+
+    /// Reports an error at the given span. While in panic mode, all errors are suppressed in order
+    /// to avoid cascading diagnostics.
+    fn error_at(&mut self, span: Span, message: impl Into<String>) {
+        self.report(span, message, false);
+    }
+
+    /// Reports an error at the given span, which may be due to the source ending prematurely (see
+    /// `Diagnostic::unexpected_end`). See `error_at`.
+    fn report(&mut self, span: Span, message: impl Into<String>, unexpected_end: bool) {
+        if self.panic_mode {
+            return;
+        }
+        self.panic_mode = true;
+        self.diagnostics.push(Diagnostic {
+            message: message.into(),
+            span,
+            unexpected_end,
+        });
+    }
+}
+
+/// The maximum number of local variables in scope, limited by the size of the slot operand.
+const LOCALS_MAX: usize = u8::MAX as usize + 1;
+
+/// The maximum number of constants in a chunk, limited by the size of the long constant operand.
+const CONSTANTS_MAX: usize = (1 << 24) - 1;
+
+/// The maximum number of upvalues of a function, limited by the size of the index operand.
+const UPVALUES_MAX: usize = u8::MAX as usize + 1;
+
+/// Returns the instruction whose operand is the given constant index: its short form if the index
+/// fits in a single byte, or else its long form (see `Ins`).
+fn constant_ins(index: u32, short: impl FnOnce(u8) -> Ins, long: impl FnOnce(u32) -> Ins) -> Ins {
+    match u8::try_from(index) {
+        Ok(index) => short(index),
+        Err(_) => long(index),
+    }
+}
+
+/// The compilation state of a function.
+struct FunctionState {
+    kind: FunctionKind,
+    arity: u8,
+    chunk: Chunk,
+    locals: Vec<Local>,
+    upvalues: Vec<Capture>,
+    /// The indexes of the name constants already added to the chunk.
+    names: Table<u32>,
+    scope_depth: usize,
+}
+
+impl FunctionState {
+    fn new(kind: FunctionKind, name: &str) -> FunctionState {
+        FunctionState {
+            kind,
+            arity: 0,
+            chunk: Chunk::new(name),
+            // The first stack slot of every call frame is reserved for the function being called.
+            // Its empty name can't be referred to by the user code. In methods, the slot holds the
+            // instance instead, which is referred to by `this`.
+            locals: vec![Local {
+                name: match kind {
+                    FunctionKind::Method | FunctionKind::Initializer => "this".into(),
+                    FunctionKind::Script | FunctionKind::Function => String::new(),
+                },
+                depth: Some(0),
+                is_captured: false,
+            }],
+            upvalues: Vec::new(),
+            names: Table::new(),
+            scope_depth: 0,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum FunctionKind {
+    Script,
+    Function,
+    Method,
+    Initializer,
+}
+
+/// The compilation state of a class.
+struct ClassState {
+    has_superclass: bool,
+}
+
+/// An identifier name and its location.
+struct Ident {
+    name: String,
+    span: Span,
+}
+
+/// A local variable, which lives in a stack slot.
+struct Local {
+    name: String,
+    /// The depth of the scope in which the variable is declared. None while the variable is not
+    /// yet initialized.
+    depth: Option<usize>,
+    /// Whether the variable is captured by some closure.
+    is_captured: bool,
+}
+
+/// The expression precedence levels, from lowest to highest.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Precedence {
+    None,
+    Assignment, // =
+    Or,         // or
+    And,        // and
+    Equality,   // == !=
+    Comparison, // < > <= >=
+    Term,       // + -
+    Factor,     // * /
+    Unary,      // ! - show typeof
+    Call,       // . ()
+    Primary,
+}
+
+impl Precedence {
+    /// Returns the immediately higher precedence level.
+    fn next(self) -> Precedence {
+        use Precedence::*;
+        match self {
+            None => Assignment,
+            Assignment => Or,
+            Or => And,
+            And => Equality,
+            Equality => Comparison,
+            Comparison => Term,
+            Term => Factor,
+            Factor => Unary,
+            Unary => Call,
+            Call | Primary => Primary,
+        }
+    }
+}
+
+type ParseFn<'s, 'v> = fn(&mut Compiler<'s, 'v>, can_assign: bool);
+
+/// Represents a row of the Pratt parser table.
+struct ParseRule<'s, 'v> {
+    prefix: Option<ParseFn<'s, 'v>>,
+    infix: Option<ParseFn<'s, 'v>>,
+    precedence: Precedence,
+}
+
+impl<'s, 'v> ParseRule<'s, 'v> {
+    /// Returns the parse rule associated with the given token kind.
+    fn of(kind: &TokenKind) -> ParseRule<'s, 'v> {
+        use TokenKind::*;
+        let (prefix, infix, precedence): (Option<ParseFn>, Option<ParseFn>, _) = match kind {
+            LeftParen => (
+                Some(Compiler::grouping),
+                Some(Compiler::call),
+                Precedence::Call,
+            ),
+            Dot => (None, Some(Compiler::dot), Precedence::Call),
+            Minus => (
+                Some(Compiler::unary),
+                Some(Compiler::binary),
+                Precedence::Term,
+            ),
+            Plus => (None, Some(Compiler::binary), Precedence::Term),
+            Slash | Star => (None, Some(Compiler::binary), Precedence::Factor),
+            Bang | Show | Typeof => (Some(Compiler::unary), None, Precedence::None),
+            BangEqual | EqualEqual => (None, Some(Compiler::binary), Precedence::Equality),
+            Greater | GreaterEqual | Less | LessEqual => {
+                (None, Some(Compiler::binary), Precedence::Comparison)
+            }
+            Identifier(_) => (Some(Compiler::variable), None, Precedence::None),
+            This => (Some(Compiler::this), None, Precedence::None),
+            Super => (Some(Compiler::super_), None, Precedence::None),
+            And => (None, Some(Compiler::and), Precedence::And),
+            Or => (None, Some(Compiler::or), Precedence::Or),
+            Number(_) => (Some(Compiler::number), None, Precedence::None),
+            String(_) => (Some(Compiler::string), None, Precedence::None),
+            Nil | True | False => (Some(Compiler::literal), None, Precedence::None),
+            _ => (None, None, Precedence::None),
+        };
+        ParseRule {
+            prefix,
+            infix,
+            precedence,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::compile;
+    use crate::{
+        pipeline::{interpret_in, Error},
+        vm::{LoxValue, Vm, VmOptions},
+    };
+
+    /// Runs the given source with and without the optimizer, returning the value of the given
+    /// global variable.
+    fn run(source: &str, global: &str) -> [Option<LoxValue>; 2] {
+        [false, true].map(|optimize| {
+            let options = VmOptions {
+                optimize,
+                ..VmOptions::default()
+            };
+            let mut vm = Vm::with_options(options);
+            interpret_in(&mut vm, source).unwrap();
+            vm.global(global)
+        })
+    }
+
+    #[test]
+    fn uses_long_operands_past_256_constants() {
+        let globals: String = (0..300).map(|i| format!("var v{i} = {i};\n")).collect();
+        let source = format!("{globals}var last = v0 + v299;");
+        assert_eq!(
+            run(&source, "last"),
+            [Some(299.0.into()), Some(299.0.into())]
+        );
+
+        let numbers: String = (0..300).map(|i| format!("{i};\n")).collect();
+        let source = format!(
+            "{numbers}
+            class A {{ init(x) {{ this.x = x; }} get() {{ return this.x; }} }}
+            class B < A {{ get() {{ return super.get() + 1; }} }}
+            fun counter() {{ var n = 0; fun next() {{ n = n + 1; return n; }} return next; }}
+            var next = counter();
+            next();
+            var late = B(next()).get();
+            late = late + B(0).x;"
+        );
+        assert_eq!(run(&source, "late"), [Some(3.0.into()), Some(3.0.into())]);
+    }
+
+    #[test]
+    fn reports_unexpected_ends() {
+        let allows_continuation = |source| {
+            let error = compile(source, &mut Vm::new()).err().unwrap();
+            error.allows_continuation()
+        };
+        for source in [
+            "if (true)",
+            "var x =",
+            "print 1 +",
+            "fun f() {",
+            "print (1",
+            "\"abc",
+        ] {
+            assert!(allows_continuation(source), "{source}");
+        }
+        for source in ["print 1 ;)", "var = 1;", "print 1 +;"] {
+            assert!(!allows_continuation(source), "{source}");
+        }
+    }
+
+    #[test]
+    fn attributes_runtime_errors_to_expressions() {
+        for (source, expression, line) in [
+            ("var a = 1;\nprint 2 * (a + \"x\");", "a + \"x\"", 2),
+            ("var b = nil;\nprint 1 +\n  b;", "1 +\n  b", 2),
+            ("print 1 <= nil;", "1 <= nil", 1),
+            ("print 1 / 0;", "1 / 0", 1),
+            ("print -\"x\";", "-\"x\"", 1),
+            ("print undefined;", "undefined", 1),
+            ("var o = nil; print o.field;", "o.field", 1),
+            ("var o = 1; o.x = 2 + 3;", "o.x = 2 + 3", 1),
+            ("var o = 1; o.method(2);", "o.method(2)", 1),
+            ("fun f(a) {}\nf(1, 2);", "f(1, 2)", 2),
+            ("throw 1 + 2;", "throw 1 + 2;", 1),
+        ] {
+            for optimize in [false, true] {
+                let mut vm = Vm::with_options(VmOptions {
+                    optimize,
+                    ..VmOptions::default()
+                });
+                let Err(Error::RuntimeError(error)) = interpret_in(&mut vm, source) else {
+                    panic!("Expected runtime error: {source}");
+                };
+                assert_eq!(&source[error.span.unwrap().range()], expression);
+                assert_eq!(error.line, line, "{source}");
+            }
+        }
+    }
+}
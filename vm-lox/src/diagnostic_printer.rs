@@ -0,0 +1,75 @@
+use std::io::{self, Write};
+
+use ansi_term::Color::Red;
+
+use crate::common::Span;
+
+/// Writes the source line which contains the given span, highlighting the span. Spans which
+/// extend over many lines are only highlighted until the end of their first line. Spans past the
+/// last non-blank character (such as the end of the file) are shown at the end of the last
+/// non-blank line, rather than on the (empty) line which follows it.
+pub fn print_span_window(writer: &mut dyn Write, source: &str, span: Span) -> io::Result<()> {
+    let lo = span.lo.min(source.trim_end().len());
+    let hi = span.hi.clamp(lo, source.len());
+    let start = line_start(source, lo);
+    let end = line_end(source, lo);
+    let line_number = source[..lo].matches('\n').count() + 1;
+
+    let before = &source[start..lo];
+    let span = &source[lo..hi.min(end)];
+    let after = &source[hi.min(end)..end];
+    writeln!(
+        writer,
+        "{line_number:>5} | {before}{}{after}\n",
+        Red.paint(span)
+    )
+}
+
+/// Writes the given source line (starting from 1), if it exists.
+pub fn print_line_window(writer: &mut dyn Write, source: &str, line: u32) -> io::Result<()> {
+    let Some(text) = source.lines().nth((line as usize).saturating_sub(1)) else {
+        return Ok(());
+    };
+    writeln!(writer, "{line:>5} | {text}\n")
+}
+
+/// Returns the index of the start of the line which contains the given index.
+fn line_start(source: &str, index: usize) -> usize {
+    source[..index].rfind('\n').map_or(0, |pos| pos + 1)
+}
+
+/// Returns the index of the end of the line which contains the given index (excluding the line
+/// break).
+fn line_end(source: &str, index: usize) -> usize {
+    source[index..]
+        .find('\n')
+        .map_or(source.len(), |pos| pos + index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span_window(source: &str, lo: usize, hi: usize) -> String {
+        let mut output = Vec::new();
+        print_span_window(&mut output, source, Span::new(lo, hi)).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn highlights_spans() {
+        let source = "var a = 1;\nprint a + b;\n";
+        let window = format!("    2 | print a + {};\n\n", Red.paint("b"));
+        assert_eq!(span_window(source, 21, 22), window);
+        let window = format!("    1 | var {}\n\n", Red.paint("a = 1;"));
+        assert_eq!(span_window(source, 4, 15), window);
+    }
+
+    #[test]
+    fn shows_the_end_of_the_file_on_the_last_line() {
+        let window = format!("    1 | print 1 +{}\n\n", Red.paint(""));
+        for source in ["print 1 +", "print 1 +\n", "print 1 +\n\n  \n"] {
+            assert_eq!(span_window(source, source.len(), source.len()), window);
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use std::fmt::{self, Write};
+
+use crate::common::{Chunk, Ins, Value};
+
+/// Returns the human-readable listing of the given chunk, followed by the listings of the chunks
+/// of every function nested in it.
+pub fn disassemble(chunk: &Chunk) -> String {
+    let mut out = String::new();
+    Disassembler::new(&mut out)
+        .program(chunk)
+        .expect("Writing to a string can't fail");
+    out
+}
+
+/// Writes the human-readable listing of chunks of bytecode. Each instruction is listed in its
+/// own row, as in:
+///
+/// ```text
+/// 0012     3 | OP_JUMP_IF_FALSE 7 -> 0022
+/// ```
+///
+/// Which holds the instruction offset, its source line (or `.` if it is the same as the one of
+/// the previous row), its name and its operand. Constant operands are followed by the constant
+/// they refer to, and jump operands are followed by the offset of their destination. The exception
+/// handlers of the chunk are listed after its code, as in:
+///
+/// ```text
+/// handler 0004..0012 -> 0015 (depth 2)
+/// ```
+///
+/// Which holds the range of the handler, its target and the stack height it unwinds to.
+pub struct Disassembler<'w, W> {
+    out: &'w mut W,
+}
+
+impl<'w, W: Write> Disassembler<'w, W> {
+    /// Creates a new disassembler which writes to the given output.
+    pub fn new(out: &'w mut W) -> Self {
+        Self { out }
+    }
+
+    /// Lists the given chunk and, recursively, the chunks of the functions in its constant table.
+    pub fn program(&mut self, chunk: &Chunk) -> fmt::Result {
+        self.chunk(chunk)?;
+        for object in chunk.constants.iter().filter_map(Value::as_object) {
+            if let Some(function) = object.as_function() {
+                writeln!(self.out)?;
+                self.program(&function.chunk)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists the given chunk.
+    pub fn chunk(&mut self, chunk: &Chunk) -> fmt::Result {
+        writeln!(self.out, "=== {} ===", chunk.name())?;
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            let line_changed = offset == 0 || chunk.line_at(offset) != chunk.line_at(offset - 1);
+            offset = self.ins(chunk, offset, line_changed)?;
+        }
+        for handler in &chunk.handlers {
+            writeln!(
+                self.out,
+                "handler {:04}..{:04} -> {:04} (depth {})",
+                handler.start, handler.end, handler.target, handler.depth
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Lists the instruction at the given offset, returning the offset of the next one.
+    pub fn ins(
+        &mut self,
+        chunk: &Chunk,
+        offset: usize,
+        show_line: bool,
+    ) -> Result<usize, fmt::Error> {
+        write!(self.out, "{offset:04} ")?;
+        if show_line {
+            write!(self.out, "{:>5}", chunk.line_at(offset))?;
+        } else {
+            self.out.write_str("    .")?;
+        }
+
+        let Some(ins) = Ins::decode(&chunk.code[offset..]) else {
+            writeln!(self.out, " | <invalid {:#04x}>", chunk.code[offset])?;
+            return Ok(offset + 1);
+        };
+        write!(self.out, " | {ins:?}")?;
+        let next = offset + ins.len();
+
+        if let Some(index) = ins.constant_index() {
+            self.constant(chunk, index)?;
+        }
+        use Ins::*;
+        match ins {
+            Jump(jump) | JumpIfFalse(jump) => write!(self.out, " -> {:04}", next + jump as usize)?,
+            Loop(jump) => match next.checked_sub(jump as usize) {
+                Some(target) => write!(self.out, " -> {target:04}")?,
+                None => self.out.write_str(" -> <invalid>")?,
+            },
+            Closure(_) | ClosureLong(_) => {
+                let index = ins.constant_index().unwrap();
+                let object = chunk
+                    .constants
+                    .get(index as usize)
+                    .and_then(Value::as_object);
+                let function = object.as_ref().and_then(|object| object.as_function());
+                // The capture descriptors aren't encoded in the bytecode, but they are listed
+                // alongside the instruction, since they are used by it.
+                for capture in function.map(|f| &f.captures[..]).unwrap_or_default() {
+                    let kind = if capture.is_local { "local" } else { "upvalue" };
+                    write!(
+                        self.out,
+                        "\n           |   capture {kind} {}",
+                        capture.index
+                    )?;
+                }
+            }
+            _ => (),
+        }
+        writeln!(self.out)?;
+        Ok(next)
+    }
+
+    fn constant(&mut self, chunk: &Chunk, index: u32) -> fmt::Result {
+        match chunk.constants.get(index as usize) {
+            Some(constant) => write!(self.out, " ({constant:?})"),
+            None => self.out.write_str(" (<invalid constant>)"),
+        }
+    }
+}
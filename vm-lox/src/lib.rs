@@ -1,8 +1,28 @@
-#![allow(dead_code)] // TODO: remove this
+//! A bytecode virtual machine for the Lox language, which may be embedded in Rust programs.
+//!
+//! A `Vm` is configured through `VmOptions`, then runs source code with `interpret_in` or
+//! precompiled bytecode (see `compile_bytecode`) with `interpret_bytecode`. Its state is kept
+//! across runs, so that the host program may define and read global variables in between
+//! (`Vm::set_global` and `Vm::global`). Rust closures may also be registered as native Lox
+//! functions (`Vm::define_native`). Values are exchanged as `LoxValue`s, which convert from and
+//! into the corresponding Rust types.
 
+mod bytecode;
 mod common;
+mod compiler;
+mod diagnostic_printer;
+mod disassembler;
+mod optimizer;
 mod pipeline;
 mod scanner;
+mod verifier;
 mod vm;
 
-pub use pipeline::{interpret, Error, Result};
+pub use bytecode::{LoadError, LoadErrorKind};
+pub use common::Span;
+pub use pipeline::{
+    compile_bytecode, disassemble, interpret, interpret_bytecode, interpret_in, interpret_repl,
+    Diagnostic, Error, Result, RuntimeError, RuntimeErrorKind, TraceFrame,
+};
+pub use verifier::{VerifyError, VerifyErrorKind};
+pub use vm::{CacheStats, FunctionProfile, LoxValue, Profile, Stats, Vm, VmOptions};
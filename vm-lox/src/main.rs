@@ -1,43 +1,209 @@
 use std::{
     env, fs,
     io::{self, Write},
-    path::Path,
+    path::{Path, PathBuf},
+    process,
 };
 
-use vm_lox::interpret;
+use repl::Repl;
+use vm_lox::{
+    compile_bytecode, disassemble, interpret_bytecode, interpret_in, Error, Vm, VmOptions,
+};
+
+mod repl;
+
+const USAGE: &str = "\
+Usage: vm-lox [options] [path]
+       vm-lox [options] compile <path> [-o <output>]
+       vm-lox [options] run <path>
+
+Runs the Lox script at the given path, or starts a REPL session if no path is given.
+
+The `compile` command compiles the script at the given path into a bytecode file (by default,
+the same path with the `.loxc` extension), which may be later run by the `run` command.
+
+Options:
+  --disassemble  Prints the compiled bytecode of the script at the given path, without running it
+  --stress-gc    Collects garbage before every allocation
+  --gc-log       Logs the heap size before and after each garbage collection
+  --trace        Prints the stack contents and each instruction before it is executed
+  --stats        Prints the execution statistics (such as the inline cache hit rates) once done
+  --profile      Prints the executed instructions per opcode and the calls, executed instructions
+                 and time of each function once done
+  --profile-folded <output>
+                 Writes the executed instructions per call stack to the given file, in the folded
+                 stacks format of the flame graph tools
+  --max-instructions <n>
+                 Aborts the execution once more than the given number of instructions are executed
+  -O0            Disables the optimization of the compiled bytecode (default)
+  -O1            Enables the peephole optimization of the compiled bytecode";
 
 fn main() -> io::Result<()> {
-    match env::args().nth(1) {
-        Some(path) => run_file(&path),
-        _ => run_repl(),
+    let mut options = VmOptions::default();
+    let mut positional = Vec::new();
+    let mut output = None;
+    let mut disassemble = false;
+    let mut reports = Reports::default();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--disassemble" => disassemble = true,
+            "--stress-gc" => options.stress_gc = true,
+            "--gc-log" => options.gc_log = true,
+            "--trace" => options.trace = true,
+            "--stats" => reports.stats = true,
+            "--profile" => reports.profile = true,
+            "-O0" => options.optimize = false,
+            "-O1" => options.optimize = true,
+            "--max-instructions" => match args.next().map(|max| max.parse()) {
+                Some(Ok(max)) => options.max_instructions = Some(max),
+                _ => usage_error("Option `--max-instructions` requires a number"),
+            },
+            "--profile-folded" => match args.next() {
+                Some(path) => reports.folded = Some(PathBuf::from(path)),
+                None => usage_error("Option `--profile-folded` requires a path"),
+            },
+            "-o" => match args.next() {
+                Some(path) => output = Some(PathBuf::from(path)),
+                None => usage_error("Option `-o` requires a path"),
+            },
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return Ok(());
+            }
+            flag if flag.starts_with('-') => usage_error(&format!("Unknown option `{flag}`")),
+            _ => positional.push(arg),
+        }
     }
-}
 
-fn run(source: &str) {
-    if source.is_empty() {
-        return;
+    let positional: Vec<_> = positional.iter().map(String::as_str).collect();
+    if output.is_some() && !matches!(positional[..], ["compile", _]) {
+        usage_error("Option `-o` can only be used with the `compile` command");
     }
+    if disassemble || matches!(positional[..], ["compile", ..]) {
+        if reports.stats {
+            usage_error("Option `--stats` can only be used when running a script");
+        }
+        if reports.profile || reports.folded.is_some() {
+            usage_error(
+                "Options `--profile` and `--profile-folded` can only be used when running a script",
+            );
+        }
+    }
+    options.profile = reports.profile || reports.folded.is_some();
+    match positional[..] {
+        ["compile" | "run", ..] if disassemble => {
+            usage_error("Option `--disassemble` can't be used with commands")
+        }
+        ["compile", path] => {
+            let output = output.unwrap_or_else(|| Path::new(path).with_extension("loxc"));
+            compile_file(path, &output, options)
+        }
+        ["run", path] => run_bytecode_file(path, options, &reports),
+        ["compile" | "run", ..] => usage_error("Expected exactly one path"),
+        [path] if disassemble => disassemble_file(path, options),
+        [path] => run_file(path, options, &reports),
+        [] if disassemble => usage_error("Option `--disassemble` requires a path"),
+        [] => run_repl(options, &reports),
+        _ => usage_error("Expected at most one path"),
+    }
+}
 
-    interpret(source).unwrap();
+fn usage_error(message: &str) -> ! {
+    eprintln!("{message}\n\n{USAGE}");
+    process::exit(64);
 }
 
-fn run_file(path: impl AsRef<Path>) -> io::Result<()> {
+fn run_file(path: impl AsRef<Path>, options: VmOptions, reports: &Reports) -> io::Result<()> {
     let source = fs::read_to_string(path)?;
-    run(&source);
+    let mut vm = Vm::with_options(options);
+    let result = interpret_in(&mut vm, &source);
+    reports.print(&vm)?;
+    if let Err(error) = result {
+        exit_with_error(&error, &source);
+    }
     Ok(())
 }
 
-fn run_repl() -> io::Result<()> {
-    loop {
-        print!(">>> ");
-        io::stdout().flush()?;
+fn disassemble_file(path: impl AsRef<Path>, options: VmOptions) -> io::Result<()> {
+    let source = fs::read_to_string(path)?;
+    match disassemble(&mut Vm::with_options(options), &source) {
+        Ok(listing) => print!("{listing}"),
+        Err(error) => exit_with_error(&error, &source),
+    }
+    Ok(())
+}
 
-        let mut source = String::new();
-        if io::stdin().read_line(&mut source)? == 0 {
-            break;
-        }
+fn compile_file(path: &str, output: &Path, options: VmOptions) -> io::Result<()> {
+    let source = fs::read_to_string(path)?;
+    match compile_bytecode(&mut Vm::with_options(options), &source, path) {
+        Ok(bytes) => fs::write(output, bytes),
+        Err(error) => exit_with_error(&error, &source),
+    }
+}
 
-        run(source.trim());
+fn run_bytecode_file(path: &str, options: VmOptions, reports: &Reports) -> io::Result<()> {
+    let bytes = fs::read(path)?;
+    let mut vm = Vm::with_options(options);
+    let result = interpret_bytecode(&mut vm, &bytes);
+    reports.print(&vm)?;
+    if let Err(error) = result {
+        // The source of a bytecode file isn't available, hence no source windows are printed.
+        exit_with_error(&error, "");
     }
     Ok(())
 }
+
+fn run_repl(options: VmOptions, reports: &Reports) -> io::Result<()> {
+    let vm = Repl::new(options).run()?;
+    reports.print(&vm)?;
+    Ok(())
+}
+
+/// The reports about the execution which are printed once done.
+#[derive(Default)]
+struct Reports {
+    /// See `--stats`.
+    stats: bool,
+    /// See `--profile`.
+    profile: bool,
+    /// See `--profile-folded`.
+    folded: Option<PathBuf>,
+}
+
+impl Reports {
+    /// Prints the requested reports about the given virtual machine (to the standard error), or
+    /// writes them to their file.
+    fn print(&self, vm: &Vm) -> io::Result<()> {
+        if self.stats {
+            eprint!("\n{}", vm.stats());
+        }
+        if let Some(profile) = vm.profile() {
+            if self.profile {
+                eprint!("\n{profile}");
+            }
+            if let Some(path) = &self.folded {
+                let mut out = io::BufWriter::new(fs::File::create(path)?);
+                profile.write_folded(&mut out)?;
+                out.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Prints the given error, alongside the windows of the given source it refers to.
+fn print_error(error: &Error, source: &str) {
+    // Errors while writing to the standard error can't be reported anyway.
+    let _ = error.render(&mut io::stderr(), source);
+}
+
+/// Prints the given error and exits with the corresponding status code (following the
+/// `sysexits.h` conventions).
+fn exit_with_error(error: &Error, source: &str) -> ! {
+    print_error(error, source);
+    process::exit(match error {
+        Error::RuntimeError(_) => 70,
+        _ => 65,
+    });
+}
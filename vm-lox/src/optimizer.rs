@@ -0,0 +1,587 @@
+use std::mem;
+
+use crate::common::{Chunk, Handler, Ins, Span, Value};
+
+/// The maximum index of a constant operand (see `Ins::ConstantLong`).
+const CONSTANTS_MAX: usize = (1 << 24) - 1;
+
+/// Optimizes the given chunk in place, by rewriting short sequences of instructions (peephole
+/// optimization). The rewrites are:
+///
+/// - Constant folding: the arithmetic over numeric constants is computed ahead of time, e.g.
+///   `Constant(1), Constant(2), Add` becomes `Constant(3)`. Divisions by zero are kept, so that
+///   they are still reported at runtime.
+/// - A pair of `Negate` (or of `Not`) is removed if the negated value is known to be a number (or
+///   a boolean).
+/// - A value which is pushed (without side effects) and immediately popped is removed, and an
+///   assignment which is popped and immediately read back (e.g. `SetLocal(1), Pop, GetLocal(1)`)
+///   keeps its value on the stack instead.
+/// - A jump to the next instruction is removed.
+///
+/// Since the jumps (and the exception handlers) are resolved after the rewrites, the rewrites never
+/// span a jump target nor a boundary of a handler range. The line and span of each remaining
+/// instruction are preserved (a folded instruction is attributed to the line of the first
+/// instruction it replaces, and to the span which covers them all).
+pub fn optimize(chunk: &mut Chunk) {
+    let mut optimizer = Optimizer::decode(chunk);
+    optimizer.rewrite();
+    optimizer.remove_empty_jumps();
+    optimizer.encode(chunk);
+}
+
+/// An instruction of the chunk being optimized.
+#[derive(Copy, Clone)]
+struct Node {
+    ins: Ins,
+    line: u32,
+    span: Span,
+    /// The index of the node the instruction jumps to, if it is a jump.
+    target: Option<usize>,
+    /// Whether some jump lands on the instruction.
+    is_target: bool,
+}
+
+struct Optimizer {
+    nodes: Vec<Node>,
+    /// The constant table, taken from the chunk until it is encoded back.
+    constants: Vec<Value>,
+    /// The exception handlers of the chunk, whose offsets are replaced by node indexes.
+    handlers: Vec<Handler>,
+}
+
+impl Optimizer {
+    /// Decodes the chunk into nodes, resolving the jumps to node indexes.
+    fn decode(chunk: &mut Chunk) -> Optimizer {
+        let mut nodes = Vec::new();
+        // The index of the node which starts at each offset.
+        let mut indexes = vec![usize::MAX; chunk.code.len() + 1];
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            let ins = Ins::decode(&chunk.code[offset..]).expect("Compiler bug. Invalid code");
+            indexes[offset] = nodes.len();
+            nodes.push(Node {
+                ins,
+                line: chunk.line_at(offset),
+                span: chunk.span_at(offset),
+                target: jump_target(offset, ins),
+                is_target: false,
+            });
+            offset += ins.len();
+        }
+        indexes[offset] = nodes.len();
+
+        for i in 0..nodes.len() {
+            if let Some(target) = nodes[i].target {
+                let target = indexes[target];
+                nodes[i].target = Some(target);
+                nodes[target].is_target = true;
+            }
+        }
+        // The boundaries of the handler ranges are treated as jump targets.
+        let mut handlers = mem::take(&mut chunk.handlers);
+        for handler in &mut handlers {
+            for offset in handler_offsets(handler) {
+                *offset = indexes[*offset as usize] as u32;
+                if let Some(node) = nodes.get_mut(*offset as usize) {
+                    node.is_target = true;
+                }
+            }
+        }
+        Optimizer {
+            nodes,
+            constants: mem::take(&mut chunk.constants),
+            handlers,
+        }
+    }
+
+    /// Applies the peephole rewrites in a single pass. Each node is appended to the output, whose
+    /// tail is then rewritten while possible.
+    fn rewrite(&mut self) {
+        // The index of each node in the output. Removed nodes are mapped to the index of the node
+        // which follows them.
+        let mut indexes = Vec::with_capacity(self.nodes.len());
+        let mut output: Vec<Node> = Vec::with_capacity(self.nodes.len());
+        for node in mem::take(&mut self.nodes) {
+            indexes.push(output.len());
+            output.push(node);
+            while self.rewrite_tail(&mut output) {}
+        }
+
+        let end = output.len();
+        let index = |index: usize| indexes.get(index).copied().unwrap_or(end);
+        for node in &mut output {
+            if let Some(target) = &mut node.target {
+                *target = index(*target);
+            }
+        }
+        for handler in &mut self.handlers {
+            for offset in handler_offsets(handler) {
+                *offset = index(*offset as usize) as u32;
+            }
+        }
+        self.nodes = output;
+    }
+
+    /// Tries to rewrite the tail of the output, returning true if it was rewritten.
+    fn rewrite_tail(&mut self, output: &mut Vec<Node>) -> bool {
+        use Ins::*;
+
+        if let Some(&[a, b, op]) = tail(output, 3) {
+            let folded = match (self.number(a.ins), self.number(b.ins), op.ins) {
+                (Some(a), Some(b), Add) => Some(a + b),
+                (Some(a), Some(b), Subtract) => Some(a - b),
+                (Some(a), Some(b), Multiply) => Some(a * b),
+                (Some(a), Some(b), Divide) if b != 0.0 => Some(a / b),
+                _ => None,
+            };
+            if let Some(number) = folded {
+                return self.replace_with_constant(output, 3, number);
+            }
+
+            let redundant = match (a.ins, b.ins, op.ins) {
+                (SetLocal(a), Pop, GetLocal(b)) => a == b,
+                (SetUpvalue(a), Pop, GetUpvalue(b)) => a == b,
+                (SetGlobal(a), Pop, GetGlobal(b)) => a == b,
+                (SetGlobalLong(a), Pop, GetGlobalLong(b)) => a == b,
+                (_, Negate, Negate) => self.is_number(a.ins),
+                (_, Not, Not) => is_bool(a.ins),
+                _ => false,
+            };
+            if redundant {
+                output.truncate(output.len() - 2);
+                return true;
+            }
+        }
+
+        if let Some(&[a, op]) = tail(output, 2) {
+            if let (Some(number), Negate) = (self.number(a.ins), op.ins) {
+                return self.replace_with_constant(output, 2, -number);
+            }
+
+            let pure = matches!(
+                a.ins,
+                Constant(_) | ConstantLong(_) | Nil | True | False | GetLocal(_) | GetUpvalue(_)
+            );
+            if pure && op.ins == Pop && !a.is_target {
+                output.truncate(output.len() - 2);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Replaces the last `len` nodes of the output with an instruction which pushes the given
+    /// number, returning true if they were replaced. They are kept if the constant table is full
+    /// or if the replacement would be longer (which could make some jump overflow its operand).
+    fn replace_with_constant(&mut self, output: &mut Vec<Node>, len: usize, number: f64) -> bool {
+        let replaced = &output[output.len() - len..];
+        let replaced_len: usize = replaced.iter().map(|node| node.ins.len()).sum();
+        let Some(ins) = self.constant(number) else {
+            return false;
+        };
+        if ins.len() > replaced_len {
+            return false;
+        }
+        let node = Node {
+            ins,
+            span: replaced[0].span.to(replaced[len - 1].span),
+            target: None,
+            ..replaced[0]
+        };
+        output.truncate(output.len() - len);
+        output.push(node);
+        true
+    }
+
+    /// Removes the jumps to the next instruction. Since removing a jump may make another one jump
+    /// to the next instruction, the nodes are considered from the last one: a forward jump is
+    /// removed if every node it jumps over is removed.
+    fn remove_empty_jumps(&mut self) {
+        let len = self.nodes.len();
+        // The index of the first node which is kept at or after each index.
+        let mut next_kept = vec![len; len + 1];
+        for i in (0..len).rev() {
+            let is_empty = matches!(self.nodes[i].target, Some(target) if target > i && next_kept[i + 1] >= target);
+            next_kept[i] = if is_empty { next_kept[i + 1] } else { i };
+        }
+
+        // The index of each node in the output. Removed nodes are mapped to the index of the node
+        // which follows them.
+        let mut indexes = Vec::with_capacity(len + 1);
+        let mut output: Vec<Node> = Vec::with_capacity(len);
+        let mut is_target = false;
+        for (i, node) in self.nodes.iter().enumerate() {
+            indexes.push(output.len());
+            is_target |= node.is_target;
+            if next_kept[i] == i {
+                output.push(Node { is_target, ..*node });
+                is_target = false;
+            }
+        }
+        indexes.push(output.len());
+
+        for node in &mut output {
+            if let Some(target) = &mut node.target {
+                *target = indexes[*target];
+            }
+        }
+        for handler in &mut self.handlers {
+            for offset in handler_offsets(handler) {
+                *offset = indexes[*offset as usize] as u32;
+            }
+        }
+        self.nodes = output;
+    }
+
+    /// Encodes the nodes back into the given chunk, computing the jump offsets.
+    fn encode(self, chunk: &mut Chunk) {
+        let mut offsets = Vec::with_capacity(self.nodes.len() + 1);
+        let mut offset = 0;
+        for node in &self.nodes {
+            offsets.push(offset);
+            offset += node.ins.len();
+        }
+        offsets.push(offset);
+
+        let mut optimized = Chunk::new(chunk.name());
+        optimized.constants = self.constants;
+        optimized.caches = mem::take(&mut chunk.caches);
+        optimized.handlers = self.handlers;
+        for handler in &mut optimized.handlers {
+            for offset in handler_offsets(handler) {
+                *offset = offsets[*offset as usize] as u32;
+            }
+        }
+        for (i, node) in self.nodes.iter().enumerate() {
+            let ins = match (node.ins, node.target) {
+                (Ins::Jump(_), Some(target)) => Ins::Jump(forward(&offsets, i, target)),
+                (Ins::JumpIfFalse(_), Some(target)) => {
+                    Ins::JumpIfFalse(forward(&offsets, i, target))
+                }
+                (Ins::Loop(_), Some(target)) => {
+                    Ins::Loop((offsets[i + 1] - offsets[target]) as u16)
+                }
+                (ins, _) => ins,
+            };
+            optimized.write(ins, node.line, node.span);
+        }
+        *chunk = optimized;
+    }
+
+    /// Returns the number pushed by the given instruction, if it is a numeric constant.
+    fn number(&self, ins: Ins) -> Option<f64> {
+        match ins {
+            Ins::Constant(index) => self.constants[index as usize].as_number(),
+            Ins::ConstantLong(index) => self.constants[index as usize].as_number(),
+            _ => None,
+        }
+    }
+
+    /// Checks if the given instruction is known to push a number.
+    fn is_number(&self, ins: Ins) -> bool {
+        use Ins::*;
+        // Unlike the other arithmetic instructions, `Add` may also push a string.
+        matches!(ins, Subtract | Multiply | Divide | Negate) || self.number(ins).is_some()
+    }
+
+    /// Returns the instruction which pushes the given number, reusing an equal constant (if any).
+    /// Returns None if the constant table is full.
+    fn constant(&mut self, number: f64) -> Option<Ins> {
+        let existing = self.constants.iter().position(|constant| {
+            // Compares the bits, so that `0` and `-0` are kept apart.
+            constant.as_number().map(f64::to_bits) == Some(number.to_bits())
+        });
+        let index = match existing {
+            Some(index) => index,
+            None if self.constants.len() <= CONSTANTS_MAX => {
+                self.constants.push(Value::from(number));
+                self.constants.len() - 1
+            }
+            None => return None,
+        };
+        Some(match u8::try_from(index) {
+            Ok(index) => Ins::Constant(index),
+            Err(_) => Ins::ConstantLong(index as u32),
+        })
+    }
+}
+
+/// Checks if the given instruction is known to push a boolean.
+fn is_bool(ins: Ins) -> bool {
+    use Ins::*;
+    matches!(ins, True | False | Not | Equal | Greater | Less)
+}
+
+/// Returns the last `len` nodes of the output, if none of them (but the first) is a jump target.
+/// Hence only the first node of a rewritten sequence may be a jump target.
+fn tail(output: &[Node], len: usize) -> Option<&[Node]> {
+    let tail = output.get(output.len().checked_sub(len)?..)?;
+    (!tail[1..].iter().any(|node| node.is_target)).then_some(tail)
+}
+
+/// Returns the offsets (or node indexes) which delimit the range and the target of the given
+/// handler.
+fn handler_offsets(handler: &mut Handler) -> [&mut u32; 3] {
+    [&mut handler.start, &mut handler.end, &mut handler.target]
+}
+
+/// Returns the offset of the forward jump from the node `from` to the node `to`.
+fn forward(offsets: &[usize], from: usize, to: usize) -> u16 {
+    (offsets[to] - offsets[from + 1]) as u16
+}
+
+/// Returns the destination offset of the given instruction (at the given offset) if it is a
+/// jump.
+fn jump_target(offset: usize, ins: Ins) -> Option<usize> {
+    let next = offset + ins.len();
+    match ins {
+        Ins::Jump(jump) | Ins::JumpIfFalse(jump) => Some(next + jump as usize),
+        Ins::Loop(jump) => Some(next - jump as usize),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compiler::compile,
+        disassembler::disassemble,
+        pipeline::interpret_in,
+        verifier::verify,
+        vm::{LoxValue, Vm, VmOptions},
+    };
+
+    /// Returns the listing of the given chunk, without the offsets and the lines of its
+    /// instructions.
+    fn listing(chunk: &Chunk) -> Vec<String> {
+        let listing = disassemble(chunk);
+        let rows = listing.lines().skip(1).map(|row| {
+            let row = row.split_once(" | ").map_or(row, |(_, ins)| ins);
+            row.split_whitespace().collect::<Vec<_>>().join(" ")
+        });
+        rows.collect()
+    }
+
+    /// Compiles the given source without optimizing it, then returns the listing of its script
+    /// before and after the optimization.
+    fn optimized(source: &str) -> (Vec<String>, Vec<String>) {
+        // The VM is kept alive, since the chunk refers to its objects.
+        let mut vm = Vm::new();
+        let mut chunk = compile(source, &mut vm).unwrap();
+        let before = listing(&chunk);
+        optimize(&mut chunk);
+        verify(&chunk).unwrap();
+        (before, listing(&chunk))
+    }
+
+    /// Runs the given source with the optimizer, returning the value of the given global variable.
+    fn run_optimized(source: &str, global: &str) -> Option<LoxValue> {
+        let options = VmOptions {
+            optimize: true,
+            ..VmOptions::default()
+        };
+        let mut vm = Vm::with_options(options);
+        interpret_in(&mut vm, source).unwrap();
+        vm.global(global)
+    }
+
+    #[test]
+    fn folds_constants() {
+        let (before, after) = optimized("print 1 + 2 * 3; print -(4 - 5); print 1 / 0;");
+        assert_eq!(
+            before,
+            [
+                "OP_CONSTANT 0 (1)",
+                "OP_CONSTANT 1 (2)",
+                "OP_CONSTANT 2 (3)",
+                "OP_MULTIPLY",
+                "OP_ADD",
+                "OP_PRINT",
+                "OP_CONSTANT 3 (4)",
+                "OP_CONSTANT 4 (5)",
+                "OP_SUBTRACT",
+                "OP_NEGATE",
+                "OP_PRINT",
+                "OP_CONSTANT 5 (1)",
+                "OP_CONSTANT 6 (0)",
+                "OP_DIVIDE",
+                "OP_PRINT",
+                "OP_NIL",
+                "OP_RETURN",
+            ]
+        );
+        // The divisions by zero are left to the runtime.
+        assert_eq!(
+            after,
+            [
+                "OP_CONSTANT 8 (7)",
+                "OP_PRINT",
+                "OP_CONSTANT 0 (1)",
+                "OP_PRINT",
+                "OP_CONSTANT 5 (1)",
+                "OP_CONSTANT 6 (0)",
+                "OP_DIVIDE",
+                "OP_PRINT",
+                "OP_NIL",
+                "OP_RETURN",
+            ]
+        );
+    }
+
+    #[test]
+    fn removes_negation_pairs() {
+        let (before, after) = optimized(
+            "var x = 1;
+            print - -(x * 2);
+            print !!(x < 2);
+            print - -x;
+            print !!x;",
+        );
+        let removed = [5, 6, 11, 12];
+        let expected: Vec<_> = (0..before.len())
+            .filter(|i| !removed.contains(i))
+            .map(|i| before[i].clone())
+            .collect();
+        assert_eq!(before[5..7], ["OP_NEGATE", "OP_NEGATE"]);
+        assert_eq!(before[11..13], ["OP_NOT", "OP_NOT"]);
+        // The operand of the other pairs isn't known to be a number (or a boolean).
+        assert_eq!(after, expected);
+        assert_eq!(after[11..13], ["OP_NEGATE", "OP_NEGATE"]);
+        assert_eq!(after[15..17], ["OP_NOT", "OP_NOT"]);
+    }
+
+    #[test]
+    fn collapses_assignments_read_back() {
+        let (before, after) = optimized("var x; x = 1; print x; { var a = 1; a = 2; print a; }");
+        assert_eq!(
+            before[3..6],
+            [
+                "OP_SET_GLOBAL 0 (\"x\")",
+                "OP_POP",
+                "OP_GET_GLOBAL 0 (\"x\")"
+            ]
+        );
+        assert_eq!(
+            before[9..12],
+            ["OP_SET_LOCAL 1", "OP_POP", "OP_GET_LOCAL 1"]
+        );
+        assert_eq!(
+            after,
+            [
+                "OP_NIL",
+                "OP_DEFINE_GLOBAL 0 (\"x\")",
+                "OP_CONSTANT 1 (1)",
+                "OP_SET_GLOBAL 0 (\"x\")",
+                "OP_PRINT",
+                "OP_CONSTANT 2 (1)",
+                "OP_CONSTANT 3 (2)",
+                "OP_SET_LOCAL 1",
+                "OP_PRINT",
+                "OP_POP",
+                "OP_NIL",
+                "OP_RETURN",
+            ]
+        );
+    }
+
+    #[test]
+    fn removes_empty_jumps() {
+        // A jump over an empty jump, which is only empty once the latter is removed, followed by
+        // a loop.
+        let mut chunk = Chunk::new("<script>");
+        for ins in [
+            Ins::True,
+            Ins::JumpIfFalse(3),
+            Ins::Jump(0),
+            Ins::Pop,
+            Ins::False,
+            Ins::JumpIfFalse(4),
+            Ins::Pop,
+            Ins::Loop(8),
+            Ins::Pop,
+            Ins::Nil,
+            Ins::Return,
+        ] {
+            chunk.write(ins, 1, Span::default());
+        }
+        verify(&chunk).unwrap();
+        assert_eq!(
+            listing(&chunk),
+            [
+                "OP_TRUE",
+                "OP_JUMP_IF_FALSE 3 -> 0007",
+                "OP_JUMP 0 -> 0007",
+                "OP_POP",
+                "OP_FALSE",
+                "OP_JUMP_IF_FALSE 4 -> 0016",
+                "OP_POP",
+                "OP_LOOP 8 -> 0008",
+                "OP_POP",
+                "OP_NIL",
+                "OP_RETURN",
+            ]
+        );
+        optimize(&mut chunk);
+        verify(&chunk).unwrap();
+        assert_eq!(
+            listing(&chunk),
+            [
+                "OP_TRUE",
+                "OP_POP",
+                "OP_FALSE",
+                "OP_JUMP_IF_FALSE 4 -> 0010",
+                "OP_POP",
+                "OP_LOOP 8 -> 0002",
+                "OP_POP",
+                "OP_NIL",
+                "OP_RETURN",
+            ]
+        );
+    }
+
+    #[test]
+    fn remaps_exception_handlers() {
+        let source = "
+            var r;
+            var a = 1 + 2;
+            try { var b = - -(2 * 3); throw b; } catch (e) { r = e; }";
+        let (before, after) = optimized(source);
+        assert_eq!(
+            before.last().unwrap(),
+            "handler 0010..0021 -> 0024 (depth 1)"
+        );
+        assert_eq!(
+            after,
+            [
+                "OP_NIL",
+                "OP_DEFINE_GLOBAL 0 (\"r\")",
+                "OP_CONSTANT 5 (3)",
+                "OP_DEFINE_GLOBAL 3 (\"a\")",
+                "OP_CONSTANT 6 (6)",
+                "OP_GET_LOCAL 1",
+                "OP_THROW",
+                "OP_POP",
+                "OP_JUMP 6 -> 0022",
+                "OP_GET_LOCAL 1",
+                "OP_SET_GLOBAL 0 (\"r\")",
+                "OP_POP",
+                "OP_POP",
+                "OP_NIL",
+                "OP_RETURN",
+                "handler 0007..0013 -> 0016 (depth 1)",
+            ]
+        );
+        assert_eq!(run_optimized(source, "r"), Some(6.0.into()));
+
+        // The handlers of a function, whose code shrinks before and within the `try` block.
+        let source = "
+            fun f(x) {
+                var y = 2 * 3;
+                try { y = - -(y + 1); y = y + x.field; } catch (e) { return y + e.line; }
+                return 0;
+            }
+            var r = f(1);";
+        assert_eq!(run_optimized(source, "r"), Some(11.0.into()));
+    }
+}
@@ -1,11 +1,171 @@
-use crate::scanner::Scanner;
+use std::{
+    error,
+    fmt::{self, Display},
+    io,
+};
+
+use crate::{
+    bytecode::{self, LoadError},
+    common::Span,
+    compiler::{compile, compile_repl},
+    diagnostic_printer::{print_line_window, print_span_window},
+    disassembler,
+    verifier::VerifyError,
+    vm::Vm,
+};
 
 /// Represents an error within the Lox interpretation pipeline.
-// TODO: impl Error + Display
 #[derive(Debug)]
 pub enum Error {
-    CompileError(String),
-    RuntimeError(String),
+    /// The source couldn't be compiled. Holds every error reported by the compiler.
+    CompileError(Vec<Diagnostic>),
+    RuntimeError(RuntimeError),
+    LoadError(LoadError),
+    VerifyError(VerifyError),
+}
+
+impl Error {
+    /// Writes the error alongside the source window(s) it refers to, given the source string it
+    /// originated from.
+    pub fn render(&self, writer: &mut dyn io::Write, source: &str) -> io::Result<()> {
+        match self {
+            Error::CompileError(diagnostics) => {
+                for diagnostic in diagnostics {
+                    writeln!(writer, "{diagnostic}\n")?;
+                    print_span_window(writer, source, diagnostic.span)?;
+                }
+            }
+            Error::RuntimeError(error) => {
+                writeln!(writer, "{error}\n")?;
+                match error.span {
+                    Some(span) if span_in_line(source, span, error.line) => {
+                        print_span_window(writer, source, span)?
+                    }
+                    _ => print_line_window(writer, source, error.line)?,
+                }
+            }
+            other => writeln!(writer, "{other}")?,
+        }
+        Ok(())
+    }
+
+    /// Checks if the source was only rejected because it ends prematurely, i.e. if every compile
+    /// error is due to its unexpected end. In such case, the REPL asks for a continuation line.
+    pub fn allows_continuation(&self) -> bool {
+        match self {
+            Error::CompileError(diagnostics) => diagnostics
+                .iter()
+                .all(|diagnostic| diagnostic.unexpected_end),
+            _ => false,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::CompileError(diagnostics) => {
+                for (i, diagnostic) in diagnostics.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    Display::fmt(diagnostic, f)?;
+                }
+                Ok(())
+            }
+            Error::RuntimeError(error) => Display::fmt(error, f),
+            Error::LoadError(error) => Display::fmt(error, f),
+            Error::VerifyError(error) => Display::fmt(error, f),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+/// Represents an error reported by the compiler, at the given span of the source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Span,
+    /// Whether the error is due to the source ending prematurely (e.g. an unclosed block or
+    /// string), hence may be fixed by appending more source to it.
+    pub unexpected_end: bool,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}; at position {}", self.message, self.span)
+    }
+}
+
+impl error::Error for Diagnostic {}
+
+/// Represents an error which aborted the execution, attributed to the source line (and span) of
+/// the offending instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeError {
+    pub kind: RuntimeErrorKind,
+    pub message: String,
+    pub line: u32,
+    /// The source span of the expression (or statement) evaluated by the offending instruction,
+    /// or None if no function was being executed.
+    pub span: Option<Span>,
+    /// The functions which were being executed when the error occurred, innermost first.
+    pub trace: Vec<TraceFrame>,
+}
+
+/// Represents the different kinds of runtime errors.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RuntimeErrorKind {
+    /// The program performed an invalid operation (e.g. a type error or an undefined variable).
+    /// Unlike the other kinds, such errors may be caught by the program (as exceptions).
+    Failure,
+    /// The program executed more instructions than allowed (see `VmOptions::max_instructions`).
+    LimitExceeded,
+    /// The execution was interrupted through the interrupt flag (see `Vm::interrupt_flag`).
+    Interrupted,
+    /// The program threw an exception (with a `throw` statement) which wasn't caught.
+    Uncaught,
+}
+
+/// An entry of the stack trace of a runtime error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceFrame {
+    pub function: String,
+    /// The source line which was being executed in the function, or None if it is a native
+    /// function.
+    pub line: Option<u32>,
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}; at line {}", self.message, self.line)?;
+        // Consecutive repeated entries (e.g. of a recursive function) are only written once.
+        let mut trace = self.trace.iter().peekable();
+        while let Some(frame) = trace.next() {
+            match frame.line {
+                Some(line) => write!(f, "\n    in {} (line {line})", frame.function)?,
+                None => write!(f, "\n    in {} (native)", frame.function)?,
+            }
+            let mut repeated = 0;
+            while trace.next_if_eq(&frame).is_some() {
+                repeated += 1;
+            }
+            if repeated > 0 {
+                write!(f, "\n    ... repeated {repeated} more times")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for RuntimeError {}
+
+/// Checks if the given span is part of the given source, and starts at the given line. Otherwise,
+/// the span was recorded for another source (e.g. a function defined by a previous REPL input).
+fn span_in_line(source: &str, span: Span, line: u32) -> bool {
+    source.get(span.range()).is_some()
+        && source[..span.lo].matches('\n').count() + 1 == line as usize
 }
 
 /// A specialized Result type for the Lox interpretation pipeline.
@@ -13,12 +173,79 @@ pub type Result<T> = std::result::Result<T, Error>;
 
 /// Runs the Lox interpretation pipeline (scanning, parsing, compiling and interpretation).
 pub fn interpret(source: &str) -> Result<()> {
-    let scanner = Scanner::new(source);
+    interpret_in(&mut Vm::new(), source)
+}
+
+/// Runs the Lox interpretation pipeline using the given virtual machine. Since the same machine
+/// may be reused across calls, its state (such as the defined global variables) is preserved.
+pub fn interpret_in(vm: &mut Vm, source: &str) -> Result<()> {
+    let chunk = compile(source, vm)?;
+    vm.interpret(chunk)
+}
+
+/// Runs the given REPL input using the given virtual machine, as `interpret_in` does, except that
+/// the value of a trailing expression statement without a semicolon is printed. If `disassemble`
+/// is set, the compiled bytecode is printed before it runs.
+pub fn interpret_repl(vm: &mut Vm, source: &str, disassemble: bool) -> Result<()> {
+    let chunk = compile_repl(source, vm)?;
+    if disassemble {
+        print!("{}", disassembler::disassemble(&chunk));
+    }
+    vm.interpret(chunk)
+}
 
-    for token in scanner {
-        let text = &source[token.span.into_range()];
-        println!("{token:?} [{text:?}]");
+/// Compiles the given source string and returns the disassembly of the resulting bytecode,
+/// without running it. See `disassembler::disassemble`.
+pub fn disassemble(vm: &mut Vm, source: &str) -> Result<String> {
+    let chunk = compile(source, vm)?;
+    Ok(disassembler::disassemble(&chunk))
+}
+
+/// Compiles the given source string (read from the given source file) into a bytecode file. See
+/// `bytecode::serialize`.
+pub fn compile_bytecode(vm: &mut Vm, source: &str, source_name: &str) -> Result<Vec<u8>> {
+    let chunk = compile(source, vm)?;
+    Ok(bytecode::serialize(&chunk, source_name))
+}
+
+/// Loads the given bytecode file and runs its script using the given virtual machine.
+pub fn interpret_bytecode(vm: &mut Vm, bytes: &[u8]) -> Result<()> {
+    let chunk = bytecode::deserialize(bytes, vm).map_err(Error::LoadError)?;
+    vm.interpret(chunk)
+}
+
+#[cfg(test)]
+mod tests {
+    use ansi_term::Color::Red;
+
+    use super::*;
+
+    /// Returns the source window rendered for the runtime error of the given source, when run
+    /// after the given setup source (using the same VM).
+    fn error_window(setup: &str, source: &str) -> String {
+        let mut vm = Vm::new();
+        interpret_in(&mut vm, setup).unwrap();
+        let error = interpret_in(&mut vm, source).unwrap_err();
+        let mut output = Vec::new();
+        error.render(&mut output, source).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        output.split_once("\n\n").unwrap().1.into()
     }
 
-    Ok(())
+    #[test]
+    fn highlights_the_failing_expressions() {
+        let window = format!("    2 | print 2 * ({});\n\n", Red.paint("a + \"x\""));
+        assert_eq!(
+            error_window("", "var a = 1;\nprint 2 * (a + \"x\");"),
+            window
+        );
+    }
+
+    #[test]
+    fn doesnt_highlight_spans_from_other_sources() {
+        // The function (hence the span) was compiled from the setup source.
+        let setup = "fun fail() {\n  return nil + 1;\n}";
+        let window = "    2 | print fail();\n\n";
+        assert_eq!(error_window(setup, "\nprint fail();"), window);
+    }
 }
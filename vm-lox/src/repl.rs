@@ -0,0 +1,126 @@
+use std::{
+    fs,
+    io::{self, Write},
+};
+
+use vm_lox::{interpret_in, interpret_repl, Vm, VmOptions};
+
+use crate::print_error;
+
+const HELP: &str = "\
+Commands:
+  :exit          Exits the REPL (as does Ctrl+D)
+  :help          Prints this message
+  :load <path>   Runs the Lox script at the given path
+  :disassemble   Toggles the printing of the compiled bytecode of each input
+  :trace         Toggles the tracing of the execution (see the `--trace` option)
+
+An input which is incomplete (e.g. an unclosed block) is continued on the next lines. The value
+of an expression which ends the input without a `;` is printed (e.g. `1 + 2`).";
+
+/// An interactive session. The same virtual machine is used for the whole session, so that the
+/// global state persists across the inputs.
+pub struct Repl {
+    vm: Vm,
+    /// The input accumulated so far, while it is incomplete.
+    source: String,
+    disassemble: bool,
+    trace: bool,
+    done: bool,
+}
+
+impl Repl {
+    pub fn new(options: VmOptions) -> Repl {
+        Repl {
+            trace: options.trace,
+            vm: Vm::with_options(options),
+            source: String::new(),
+            disassemble: false,
+            done: false,
+        }
+    }
+
+    /// Runs the session until it is exited, then returns its virtual machine.
+    pub fn run(mut self) -> io::Result<Vm> {
+        eprintln!("Welcome to vm-lox. Enter Ctrl+D or `:exit` to exit, or `:help` for help.\n");
+        while !self.done {
+            let (line, is_eof) = self.read_line()?;
+            if let Some(command) = line.trim().strip_prefix(':') {
+                self.handle_command(command);
+                continue;
+            }
+            self.source += &line;
+            if self.source.trim().is_empty() {
+                self.source.clear();
+                continue;
+            }
+            // Once the input ends, an incomplete input is reported as is.
+            if self.eval(!is_eof) {
+                self.source.clear();
+            }
+        }
+        Ok(self.vm)
+    }
+
+    /// Reads the next line (including its line break), returning it alongside whether the input
+    /// has ended.
+    fn read_line(&mut self) -> io::Result<(String, bool)> {
+        let prompt = if self.source.is_empty() { ">>>" } else { "..." };
+        print!("{prompt} ");
+        io::stdout().flush()?;
+
+        let mut line = String::new();
+        let is_eof = io::stdin().read_line(&mut line)? == 0;
+        self.done = is_eof && self.source.is_empty();
+        if is_eof {
+            println!();
+        }
+        Ok((line, is_eof))
+    }
+
+    /// Compiles and runs the accumulated input, printing its errors (if any). If the input may be
+    /// continued and is incomplete, nothing is done and false is returned. The value of a trailing
+    /// expression (without a semicolon) is printed, as in `1 + 2`.
+    fn eval(&mut self, may_continue: bool) -> bool {
+        match interpret_repl(&mut self.vm, &self.source, self.disassemble) {
+            Err(error) if may_continue && error.allows_continuation() => return false,
+            Err(error) => print_error(&error, &self.source),
+            Ok(()) => (),
+        }
+        true
+    }
+
+    fn handle_command(&mut self, command: &str) {
+        let args: Vec<_> = command.split_ascii_whitespace().collect();
+        match args[..] {
+            ["exit"] => self.done = true,
+            ["help"] => eprintln!("{HELP}"),
+            ["load", path] => self.load(path),
+            ["load", ..] => eprintln!("Command `:load` expects exactly one path."),
+            ["disassemble"] => toggle("disassemble", &mut self.disassemble),
+            ["trace"] => {
+                toggle("trace", &mut self.trace);
+                self.vm.set_trace(self.trace);
+            }
+            _ => eprintln!("Invalid command. Type `:help` for guidance."),
+        }
+    }
+
+    /// Runs the script at the given path, as if it was entered.
+    fn load(&mut self, path: &str) {
+        match fs::read_to_string(path) {
+            Ok(source) => {
+                if let Err(error) = interpret_in(&mut self.vm, &source) {
+                    print_error(&error, &source);
+                }
+            }
+            Err(error) => eprintln!("Couldn't read `{path}`: {error}"),
+        }
+    }
+}
+
+fn toggle(name: &str, option: &mut bool) {
+    *option = !*option;
+    let status = if *option { "ON" } else { "OFF" };
+    println!("Toggled `{name}` option {status}.");
+}
@@ -103,18 +103,14 @@ impl Scanner<'_> {
     fn skip_whitespace_and_comment(&mut self) {
         loop {
             match self.peek_first() {
-                '/' => {
-                    if self.peek_second() == '/' {
-                        while self.peek_first() != '\n' && !self.is_at_end() {
-                            self.bump();
-                        }
-                    } else {
-                        // The first slash must not be consumed if there is no second slash that
-                        // indicates the start of a comment token (which is in fact ignored).
-                        //
-                        // In such case, this function is returned since the first slash must be
-                        // accounted for by the "main" scanner implementation, in `scan_kind`.
-                        return;
+                // The first slash must not be consumed if there is no second slash that indicates
+                // the start of a comment token (which is in fact ignored).
+                //
+                // In such case, this function is returned (by the last arm) since the first slash
+                // must be accounted for by the "main" scanner implementation, in `scan_kind`.
+                '/' if self.peek_second() == '/' => {
+                    while self.peek_first() != '\n' && !self.is_at_end() {
+                        self.bump();
                     }
                 }
 
@@ -137,9 +133,8 @@ impl Scanner<'_> {
     fn bump(&mut self) -> char {
         self.source_iter
             .next()
-            .map(|char| {
+            .inspect(|char| {
                 self.lexme_end += char.len_utf8();
-                char
             })
             .unwrap_or_else(|| {
                 if self.done {
@@ -183,7 +178,7 @@ impl Scanner<'_> {
     fn slice(&self, left_modifier: usize, right_modifier: usize) -> &str {
         let left = self.lexme_start + left_modifier;
         let right = self.lexme_end - right_modifier;
-        debug_assert!(left <= left, "Invalid computed bounds");
+        debug_assert!(left <= right, "Invalid computed bounds");
         &self.source[left..right]
     }
 
@@ -205,6 +200,11 @@ impl<'s> Scanner<'s> {
             done: false,
         }
     }
+
+    /// Checks if every character of the source string was scanned.
+    pub fn is_exhausted(&self) -> bool {
+        self.is_at_end()
+    }
 }
 
 impl Iterator for Scanner<'_> {
@@ -239,6 +239,9 @@ static KEYWORDS_MAP: phf::Map<&'static str, TokenKind> = phf::phf_map! {
     "print"  => TokenKind::Print,
     "typeof" => TokenKind::Typeof,
     "show"   => TokenKind::Show,
+    "try"    => TokenKind::Try,
+    "catch"  => TokenKind::Catch,
+    "throw"  => TokenKind::Throw,
 };
 
 /// Checks if the given char is valid as an identifier's start character.
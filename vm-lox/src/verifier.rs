@@ -0,0 +1,491 @@
+use std::{
+    error::Error,
+    fmt::{self, Display},
+};
+
+use crate::common::{Capture, Chunk, Ins, ObjectRef, OpCode, Value};
+
+/// Verifies the chunk of a script (and the chunks of every function nested in it), so that it
+/// may be safely executed by the virtual machine even if it wasn't produced by the compiler.
+///
+/// The verification ensures that:
+///
+/// - Every instruction has a valid opcode and a complete operand.
+/// - Every constant operand refers to an existing constant of the expected type (e.g. names must
+///   be strings and closures must refer to functions).
+/// - Every local variable operand refers to a slot below the top of the stack, every upvalue
+///   operand refers to one of the function's captures, and every cache operand refers to one of
+///   the chunk's inline caches.
+/// - Every jump lands on the start of an instruction.
+/// - Every exception handler covers a range of whole instructions and targets the start of an
+///   instruction.
+/// - The stack never underflows, has the same height whenever an instruction is reached (whatever
+///   the path the execution took to reach it), and the execution never falls off the end of the
+///   code. In the range of each exception handler, the stack is never lower than the height it
+///   is unwound to.
+///
+/// The types of the values on the stack are not verified: every instruction which expects a value
+/// of some type (e.g. the superclass of `GetSuper` or the closure of `Method`) checks it at
+/// runtime, and reports a runtime error otherwise.
+pub fn verify(chunk: &Chunk) -> Result<(), VerifyError> {
+    verify_function(chunk, 0, &[])
+}
+
+/// Represents a verification failure, at the given offset of the code of the given function.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    pub kind: VerifyErrorKind,
+    /// The name of the function whose code failed the verification.
+    pub function: String,
+    pub offset: usize,
+}
+
+/// Represents the different kinds of verification failures.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyErrorKind {
+    InvalidOpcode(u8),
+    TruncatedOperand,
+    ConstantOutOfBounds(u32),
+    UnexpectedConstant { index: u32, expected: &'static str },
+    LocalOutOfBounds(u8),
+    UpvalueOutOfBounds(u8),
+    CacheOutOfBounds(u16),
+    InvalidJumpTarget,
+    InvalidHandler,
+    StackUnderflow,
+    StackMismatch { expected: usize, found: usize },
+    FallsOffEnd,
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use VerifyErrorKind::*;
+        match self.kind {
+            InvalidOpcode(byte) => write!(f, "Invalid opcode {byte:#04x}")?,
+            TruncatedOperand => f.write_str("Truncated instruction operand")?,
+            ConstantOutOfBounds(index) => write!(f, "Constant {index} out of bounds")?,
+            UnexpectedConstant { index, expected } => {
+                write!(f, "Expected constant {index} to be a {expected}")?
+            }
+            LocalOutOfBounds(slot) => write!(f, "Local variable slot {slot} out of bounds")?,
+            UpvalueOutOfBounds(index) => write!(f, "Upvalue {index} out of bounds")?,
+            CacheOutOfBounds(index) => write!(f, "Inline cache {index} out of bounds")?,
+            InvalidJumpTarget => f.write_str("Jump doesn't land on an instruction")?,
+            InvalidHandler => f.write_str("Invalid exception handler")?,
+            StackUnderflow => f.write_str("Stack underflow")?,
+            StackMismatch { expected, found } => write!(
+                f,
+                "Inconsistent stack height (expected {expected}, but found {found})"
+            )?,
+            FallsOffEnd => f.write_str("Execution falls off the end of the code")?,
+        }
+        write!(f, "; in `{}` at offset {:04}", self.function, self.offset)
+    }
+}
+
+impl Error for VerifyError {}
+
+/// Verifies the chunk of a function with the given arity and captures.
+fn verify_function(chunk: &Chunk, arity: u8, captures: &[Capture]) -> Result<(), VerifyError> {
+    let error = |offset, kind| VerifyError {
+        kind,
+        function: chunk.name().to_owned(),
+        offset,
+    };
+
+    // Decodes every instruction, so that their boundaries are known.
+    let mut decoded = vec![None; chunk.code.len()];
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let Some(ins) = Ins::decode(&chunk.code[offset..]) else {
+            let byte = chunk.code[offset];
+            return Err(match OpCode::from_byte(byte) {
+                Some(_) => error(offset, VerifyErrorKind::TruncatedOperand),
+                None => error(offset, VerifyErrorKind::InvalidOpcode(byte)),
+            });
+        };
+        decoded[offset] = Some(ins);
+        offset += ins.len();
+    }
+
+    // Checks the operands which don't depend on the stack.
+    for (offset, ins) in decoded.iter().enumerate() {
+        let Some(ins) = *ins else { continue };
+        check_constant(chunk, ins).map_err(|kind| error(offset, kind))?;
+        match ins {
+            Ins::GetUpvalue(index) | Ins::SetUpvalue(index) if index as usize >= captures.len() => {
+                return Err(error(offset, VerifyErrorKind::UpvalueOutOfBounds(index)));
+            }
+            Ins::GetProperty { cache, .. }
+            | Ins::GetPropertyLong { cache, .. }
+            | Ins::Invoke { cache, .. }
+            | Ins::InvokeLong { cache, .. }
+            | Ins::SuperInvoke { cache, .. }
+            | Ins::SuperInvokeLong { cache, .. }
+                if cache as usize >= chunk.caches.len() =>
+            {
+                return Err(error(offset, VerifyErrorKind::CacheOutOfBounds(cache)));
+            }
+            Ins::Closure(_) | Ins::ClosureLong(_) => {
+                let function = function_at(chunk, ins.constant_index().unwrap()).unwrap();
+                for capture in &function.as_function().unwrap().captures {
+                    if !capture.is_local && capture.index as usize >= captures.len() {
+                        return Err(error(
+                            offset,
+                            VerifyErrorKind::UpvalueOutOfBounds(capture.index),
+                        ));
+                    }
+                }
+            }
+            _ => (),
+        }
+        if let Some(target) = jump_target(offset, ins) {
+            if decoded.get(target).copied().flatten().is_none() {
+                return Err(error(offset, VerifyErrorKind::InvalidJumpTarget));
+            }
+        }
+    }
+
+    let is_boundary = |offset: u32| {
+        offset as usize == chunk.code.len()
+            || decoded.get(offset as usize).is_some_and(Option::is_some)
+    };
+    for handler in &chunk.handlers {
+        let is_valid = handler.start <= handler.end
+            && is_boundary(handler.start)
+            && is_boundary(handler.end)
+            && decoded
+                .get(handler.target as usize)
+                .is_some_and(Option::is_some);
+        if !is_valid {
+            return Err(error(
+                handler.start as usize,
+                VerifyErrorKind::InvalidHandler,
+            ));
+        }
+    }
+
+    // Computes the stack height before each instruction, following every path of the execution.
+    // The slot of the callee (or receiver) and the arguments are already on the stack, while the
+    // exception is on top of the unwound stack at the start of each handler.
+    let mut heights = vec![None; chunk.code.len()];
+    let mut pending = vec![(0, arity as usize + 1)];
+    for handler in &chunk.handlers {
+        pending.push((handler.target as usize, handler.depth as usize + 1));
+    }
+    while let Some((offset, height)) = pending.pop() {
+        let Some(ins) = decoded.get(offset).copied().flatten() else {
+            // Only reached by falling through the last instruction, since the jumps were checked.
+            return Err(error(offset, VerifyErrorKind::FallsOffEnd));
+        };
+        match heights[offset] {
+            Some(expected) if expected == height => continue,
+            Some(expected) => {
+                return Err(error(
+                    offset,
+                    VerifyErrorKind::StackMismatch {
+                        expected,
+                        found: height,
+                    },
+                ));
+            }
+            None => heights[offset] = Some(height),
+        }
+
+        match ins {
+            Ins::GetLocal(slot) | Ins::SetLocal(slot) if slot as usize >= height => {
+                return Err(error(offset, VerifyErrorKind::LocalOutOfBounds(slot)));
+            }
+            Ins::Closure(_) | Ins::ClosureLong(_) => {
+                let function = function_at(chunk, ins.constant_index().unwrap()).unwrap();
+                for capture in &function.as_function().unwrap().captures {
+                    if capture.is_local && capture.index as usize >= height {
+                        return Err(error(
+                            offset,
+                            VerifyErrorKind::LocalOutOfBounds(capture.index),
+                        ));
+                    }
+                }
+            }
+            _ => (),
+        }
+
+        let (pops, pushes) = ins.stack_effect();
+        if height < pops {
+            return Err(error(offset, VerifyErrorKind::StackUnderflow));
+        }
+        let height = height - pops + pushes;
+        let next = offset + ins.len();
+        match ins {
+            Ins::Return | Ins::Throw => (),
+            Ins::Jump(_) | Ins::Loop(_) => {
+                pending.push((jump_target(offset, ins).unwrap(), height));
+            }
+            Ins::JumpIfFalse(_) => {
+                pending.push((jump_target(offset, ins).unwrap(), height));
+                pending.push((next, height));
+            }
+            _ => pending.push((next, height)),
+        }
+    }
+
+    for handler in &chunk.handlers {
+        let range = handler.start as usize..handler.end as usize;
+        let below = heights[range.clone()]
+            .iter()
+            .position(|height| height.is_some_and(|height| height < handler.depth as usize));
+        if let Some(index) = below {
+            return Err(error(range.start + index, VerifyErrorKind::InvalidHandler));
+        }
+    }
+
+    // Verifies the nested functions.
+    for object in chunk.constants.iter().filter_map(Value::as_object) {
+        if let Some(function) = object.as_function() {
+            verify_function(&function.chunk, function.arity, &function.captures)?;
+        }
+    }
+    Ok(())
+}
+
+/// Checks the constant operand of the given instruction, if it has one.
+fn check_constant(chunk: &Chunk, ins: Ins) -> Result<(), VerifyErrorKind> {
+    type Expected = Option<(&'static str, fn(&Value) -> bool)>;
+    const STRING: Expected = Some(("string", |value| value.as_str().is_some()));
+    const FUNCTION: Expected = Some(("function", |value| {
+        value
+            .as_object()
+            .is_some_and(|object| object.as_function().is_some())
+    }));
+
+    use Ins::*;
+    let Some(index) = ins.constant_index() else {
+        return Ok(());
+    };
+    let expected = match ins {
+        Constant(_) | ConstantLong(_) => None,
+        Closure(_) | ClosureLong(_) => FUNCTION,
+        // The other constants are names.
+        _ => STRING,
+    };
+    let Some(constant) = chunk.constants.get(index as usize) else {
+        return Err(VerifyErrorKind::ConstantOutOfBounds(index));
+    };
+    match expected {
+        Some((expected, is_expected)) if !is_expected(constant) => {
+            Err(VerifyErrorKind::UnexpectedConstant { index, expected })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Returns the function constant with the given index, if any.
+fn function_at(chunk: &Chunk, index: u32) -> Option<ObjectRef> {
+    let object = chunk.constants.get(index as usize)?.as_object()?;
+    object.as_function().is_some().then_some(object)
+}
+
+/// Returns the destination of the given instruction (at the given offset) if it is a jump. A
+/// destination before the start of the code wraps around, so that it is also out of bounds.
+fn jump_target(offset: usize, ins: Ins) -> Option<usize> {
+    let next = offset + ins.len();
+    match ins {
+        Ins::Jump(jump) | Ins::JumpIfFalse(jump) => Some(next + jump as usize),
+        Ins::Loop(jump) => Some(next.wrapping_sub(jump as usize)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::{Function, Handler, ObjectKind, Span},
+        pipeline::{Error, RuntimeError, RuntimeErrorKind},
+        vm::Vm,
+    };
+
+    /// Returns a chunk with the given code and constants.
+    fn chunk(code: &[Ins], constants: &[Value]) -> Chunk {
+        let mut chunk = Chunk::new("<script>");
+        for &ins in code {
+            chunk.write(ins, 1, Span::default());
+        }
+        for &constant in constants {
+            chunk.add_constant(constant);
+        }
+        chunk
+    }
+
+    fn verify_error(chunk: &Chunk) -> VerifyErrorKind {
+        verify(chunk).unwrap_err().kind
+    }
+
+    /// Checks that the given chunk is verified, but fails at runtime with the given message.
+    fn assert_runtime_error(vm: &mut Vm, chunk: Chunk, expected: &str) {
+        verify(&chunk).unwrap();
+        match vm.interpret(chunk) {
+            Err(Error::RuntimeError(RuntimeError { kind, message, .. })) => {
+                assert_eq!(kind, RuntimeErrorKind::Failure);
+                assert_eq!(message, expected);
+            }
+            result => panic!("Expected a runtime error, got {result:?}"),
+        }
+    }
+
+    #[test]
+    fn accepts_well_formed_code() {
+        use Ins::*;
+        let chunk = chunk(&[Nil, JumpIfFalse(1), Not, Pop, Nil, Return], &[]);
+        verify(&chunk).unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_opcodes_and_operands() {
+        let mut chunk = chunk(&[], &[]);
+        chunk.code = vec![0xff];
+        assert_eq!(verify_error(&chunk), VerifyErrorKind::InvalidOpcode(0xff));
+        chunk.code = vec![OpCode::Constant as u8];
+        assert_eq!(verify_error(&chunk), VerifyErrorKind::TruncatedOperand);
+    }
+
+    #[test]
+    fn rejects_invalid_constants() {
+        use Ins::*;
+        let number = Value::from(1.0);
+        let chunk_ = chunk(&[Constant(1), Return], &[number]);
+        assert_eq!(
+            verify_error(&chunk_),
+            VerifyErrorKind::ConstantOutOfBounds(1)
+        );
+        let chunk_ = chunk(&[GetGlobal(0), Return], &[number]);
+        assert_eq!(
+            verify_error(&chunk_),
+            VerifyErrorKind::UnexpectedConstant {
+                index: 0,
+                expected: "string"
+            }
+        );
+        let chunk_ = chunk(&[GetGlobalLong(0), Return], &[number]);
+        assert_eq!(
+            verify_error(&chunk_),
+            VerifyErrorKind::UnexpectedConstant {
+                index: 0,
+                expected: "string"
+            }
+        );
+        let chunk_ = chunk(&[Closure(0), Return], &[number]);
+        assert_eq!(
+            verify_error(&chunk_),
+            VerifyErrorKind::UnexpectedConstant {
+                index: 0,
+                expected: "function"
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_out_of_bounds_operands() {
+        use Ins::*;
+        let chunk_ = chunk(&[GetLocal(1), Return], &[]);
+        assert_eq!(verify_error(&chunk_), VerifyErrorKind::LocalOutOfBounds(1));
+        let chunk_ = chunk(&[GetUpvalue(0), Return], &[]);
+        assert_eq!(
+            verify_error(&chunk_),
+            VerifyErrorKind::UpvalueOutOfBounds(0)
+        );
+        let mut vm = Vm::new();
+        let name = Value::from(vm.heap.intern("m"));
+        let chunk_ = chunk(&[GetProperty { name: 0, cache: 0 }, Return], &[name]);
+        assert_eq!(verify_error(&chunk_), VerifyErrorKind::CacheOutOfBounds(0));
+    }
+
+    #[test]
+    fn rejects_invalid_control_flow() {
+        use Ins::*;
+        // Lands on the operand of the `Constant`.
+        let chunk_ = chunk(&[Jump(1), Constant(0), Return], &[Value::NIL]);
+        assert_eq!(verify_error(&chunk_), VerifyErrorKind::InvalidJumpTarget);
+        let chunk_ = chunk(&[Nil], &[]);
+        assert_eq!(verify_error(&chunk_), VerifyErrorKind::FallsOffEnd);
+        let chunk_ = chunk(&[Pop, Pop, Nil, Return], &[]);
+        assert_eq!(verify_error(&chunk_), VerifyErrorKind::StackUnderflow);
+        // The branch which skips the `Nil` reaches the `Return` with a lower stack.
+        let chunk_ = chunk(&[True, JumpIfFalse(1), Nil, Return], &[]);
+        assert_eq!(
+            verify_error(&chunk_),
+            VerifyErrorKind::StackMismatch {
+                expected: 3,
+                found: 2
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_handlers() {
+        use Ins::*;
+        let mut chunk = chunk(&[Constant(0), Throw, Nil, Return], &[Value::NIL]);
+        let handler = Handler {
+            start: 0,
+            end: 3,
+            target: 3,
+            depth: 1,
+        };
+        chunk.handlers = vec![handler];
+        verify(&chunk).unwrap();
+        // The range ends in the middle of the `Constant`.
+        chunk.handlers = vec![Handler { end: 1, ..handler }];
+        assert_eq!(verify_error(&chunk), VerifyErrorKind::InvalidHandler);
+        chunk.handlers = vec![Handler {
+            target: 1,
+            ..handler
+        }];
+        assert_eq!(verify_error(&chunk), VerifyErrorKind::InvalidHandler);
+        // The stack of the range is lower than the height it is unwound to.
+        chunk.handlers = vec![Handler {
+            depth: 3,
+            ..handler
+        }];
+        assert_eq!(verify_error(&chunk), VerifyErrorKind::InvalidHandler);
+    }
+
+    #[test]
+    fn checks_superclasses_at_runtime() {
+        use Ins::*;
+        let mut vm = Vm::new();
+        let name = Value::from(vm.heap.intern("m"));
+        let message = "Superclass must be a class";
+        let chunk_ = chunk(&[Nil, Nil, GetSuper(0), Pop, Nil, Return], &[name]);
+        assert_runtime_error(&mut vm, chunk_, message);
+
+        let invoke = SuperInvoke {
+            name: 0,
+            argc: 0,
+            cache: 0,
+        };
+        let mut chunk_ = chunk(&[Nil, Nil, invoke, Pop, Nil, Return], &[name]);
+        chunk_.add_cache();
+        assert_runtime_error(&mut vm, chunk_, message);
+
+        let chunk_ = chunk(&[Nil, Class(0), Inherit, Pop, Nil, Return], &[name]);
+        assert_runtime_error(&mut vm, chunk_, message);
+        let chunk_ = chunk(&[Class(0), Nil, Inherit, Pop, Nil, Return], &[name]);
+        assert_runtime_error(&mut vm, chunk_, "Subclass must be a class");
+    }
+
+    #[test]
+    fn checks_methods_at_runtime() {
+        use Ins::*;
+        let mut vm = Vm::new();
+        let name = Value::from(vm.heap.intern("m"));
+        let chunk_ = chunk(&[Class(0), Nil, Method(0), Pop, Nil, Return], &[name]);
+        assert_runtime_error(&mut vm, chunk_, "Method must be a function");
+
+        let body = chunk(&[Nil, Return], &[]);
+        let function = ObjectKind::Function(Function::new(0, Vec::new(), body));
+        let function = Value::from(vm.heap.alloc(function));
+        let code = [Nil, Closure(1), Method(0), Pop, Nil, Return];
+        let chunk_ = chunk(&code, &[name, function]);
+        assert_runtime_error(&mut vm, chunk_, "Methods must be defined in a class");
+    }
+}
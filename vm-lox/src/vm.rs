@@ -1,62 +1,1291 @@
-use crate::common::{Chunk, Ins, Value};
+use std::{
+    cell::Cell,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    common::{
+        self, BoundMethod, CachedProperty, Chunk, Closure, Function, Handler, Heap, InlineCache,
+        Instance, NativeFn, NativeFunction, ObjectKind, ObjectRef, OpCode, Table, Upvalue,
+        UpvalueState, Value,
+    },
+    disassembler::Disassembler,
+    pipeline::{Error, Result, RuntimeError, RuntimeErrorKind, TraceFrame},
+    verifier::verify,
+};
+
+mod host;
+mod natives;
+mod profile;
+mod stats;
+
+pub use host::LoxValue;
+pub use profile::{FunctionProfile, Profile};
+pub use stats::{CacheStats, Stats};
+
+/// The maximum number of nested calls. Since the stack grows as needed, this only bounds runaway
+/// recursions.
+const FRAMES_MAX: usize = 4096;
+
+/// The initial capacity of the stack (in values).
+const STACK_INITIAL: usize = 1024;
 
 /// The virtual machine.
 pub struct Vm {
+    frames: Vec<CallFrame>,
     stack: Vec<Value>,
+    globals: Table<Value>,
+    /// The upvalues which still refer to a stack slot, sorted by their slots.
+    open_upvalues: Vec<ObjectRef>,
+    /// The interned name of class initializers.
+    init_string: ObjectRef,
+    /// The class of the exceptions thrown by the failed operations (see `catch_error`).
+    error_class: ObjectRef,
+    pub(crate) heap: Heap,
+    /// Whether the chunks compiled for this VM are optimized.
+    pub(crate) optimize: bool,
+    /// Whether the execution is traced (see `VmOptions::trace`).
+    trace: bool,
+    /// The number of instructions executed by the ongoing interpretation.
+    executed: u64,
+    /// See `VmOptions::max_instructions`.
+    max_instructions: Option<u64>,
+    /// See `VmOptions::interrupt`.
+    interrupt: Arc<AtomicBool>,
+    stats: Stats,
+    /// See `VmOptions::profile`.
+    profile: Option<Profile>,
+}
+
+/// The virtual machine options.
+#[derive(Debug, Clone, Default)]
+pub struct VmOptions {
+    /// If true, the garbage collector runs before every allocation. Useful to find objects which
+    /// are not properly rooted.
+    pub stress_gc: bool,
+    /// If true, the number of allocated bytes is logged before and after each garbage collection.
+    pub gc_log: bool,
+    /// If true, the compiled chunks are optimized (see `optimizer::optimize`).
+    pub optimize: bool,
+    /// If true, the stack contents and the instruction are printed (to the standard error) before
+    /// each instruction is executed. May be changed later with `Vm::set_trace`.
+    pub trace: bool,
+    /// The maximum number of instructions executed by each interpretation (i.e. each call to
+    /// `Vm::interpret`), if any. Once exceeded, the execution is aborted with a runtime error. May
+    /// be changed later with `Vm::set_max_instructions`.
+    pub max_instructions: Option<u64>,
+    /// A flag which may be set (e.g. from another thread) to abort the ongoing execution with a
+    /// runtime error. The flag is cleared once the execution is aborted. If None, a new flag is
+    /// created (see `Vm::interrupt_flag`).
+    pub interrupt: Option<Arc<AtomicBool>>,
+    /// If true, the executed instructions and the time spent in each function are recorded (see
+    /// `Vm::profile`), which slows down the execution.
+    pub profile: bool,
+}
+
+/// Represents an ongoing function call.
+struct CallFrame {
+    /// The closure being executed.
+    closure: ObjectRef,
+    /// The function of the closure, cached to avoid an indirection on every read of its code.
+    function: ObjectRef,
+    /// The index of the next instruction to be executed in the function's chunk.
+    ip: usize,
+    /// The index of the first stack slot that belongs to this frame (which holds the callee).
+    slot_base: usize,
 }
 
 impl Vm {
     pub fn new() -> Vm {
-        Self {
-            stack: Vec::with_capacity(256),
+        Vm::with_options(VmOptions::default())
+    }
+
+    pub fn with_options(options: VmOptions) -> Vm {
+        let mut heap = Heap::new();
+        heap.stress = options.stress_gc;
+        heap.log = options.gc_log;
+        let error_name = heap.intern("Error");
+        let error_class = heap.alloc(ObjectKind::Class(common::Class::new(error_name)));
+        let mut vm = Self {
+            frames: Vec::with_capacity(FRAMES_MAX),
+            stack: Vec::with_capacity(STACK_INITIAL),
+            globals: Table::new(),
+            open_upvalues: Vec::new(),
+            init_string: heap.intern("init"),
+            error_class,
+            heap,
+            optimize: options.optimize,
+            trace: options.trace,
+            executed: 0,
+            max_instructions: options.max_instructions,
+            interrupt: options.interrupt.unwrap_or_default(),
+            stats: Stats::default(),
+            profile: options.profile.then(Profile::new),
+        };
+        vm.register_native("clock", 0, Box::new(natives::clock));
+        vm
+    }
+
+    /// Interprets the given chunk as the top-level script.
+    pub(crate) fn interpret(&mut self, chunk: Chunk) -> Result<()> {
+        // The chunk may not come from the compiler (e.g. it was loaded from a bytecode file).
+        verify(&chunk).map_err(Error::VerifyError)?;
+        let function = self.alloc(ObjectKind::Function(Function::new(0, Vec::new(), chunk)));
+        let script = self.alloc(ObjectKind::Closure(Closure {
+            function,
+            upvalues: Box::new([]),
+        }));
+        self.push(Value::from(script));
+        self.executed = 0;
+        self.call(script, 0)?;
+        let result = self.run();
+        if let Some(profile) = &mut self.profile {
+            // Records the return (or the abort) of the script.
+            profile.sync(&self.frames);
+        }
+        result
+    }
+
+    /// Enables or disables the tracing of the execution (see `VmOptions::trace`).
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    /// Sets the maximum number of instructions executed by each interpretation (see
+    /// `VmOptions::max_instructions`).
+    pub fn set_max_instructions(&mut self, max_instructions: Option<u64>) {
+        self.max_instructions = max_instructions;
+    }
+
+    /// Returns the flag which interrupts the execution once set (see `VmOptions::interrupt`).
+    pub fn interrupt_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.interrupt)
+    }
+
+    /// Returns the execution statistics.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Returns the execution profile, if profiling is enabled (see `VmOptions::profile`).
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Runs the script until it returns. The runtime errors raised by the failed operations are
+    /// thrown as exceptions (see `catch_error`), while the other ones abort the execution. Once
+    /// aborted, the stack is reset so that the VM may be reused.
+    fn run(&mut self) -> Result<()> {
+        loop {
+            let result = match self.execute() {
+                Ok(()) => return Ok(()),
+                Err(Error::RuntimeError(error)) if error.kind == RuntimeErrorKind::Failure => {
+                    self.catch_error(error)
+                }
+                Err(error) => Err(error),
+            };
+            if let Err(error) = result {
+                self.reset();
+                return Err(error);
+            }
         }
     }
 
-    pub fn interpret(&mut self, chunk: Chunk) -> Result<(), ()> {
-        for ins in chunk.code {
-            use Ins::*;
-            match ins {
-                Constant(value) => {
+    /// Executes the instructions until the script returns or some error is raised.
+    ///
+    /// The chunk and the instruction pointer of the current frame are kept in locals, rather than
+    /// read through the frame for each byte. The instruction pointer is only stored back in the
+    /// frame before the frames are inspected (by the runtime errors, the calls and the exceptions),
+    /// and both are reloaded once the current frame changes.
+    fn execute(&mut self) -> Result<()> {
+        let mut chunk: &Chunk;
+        let mut ip: usize;
+        macro_rules! load_frame {
+            () => {{
+                let frame = self.frame();
+                let function = frame.function.as_function().unwrap();
+                // SAFETY: The function of a frame is kept alive by its closure (see
+                // `collect_garbage`), and its chunk is never modified once it runs. The chunk is
+                // only used while its frame is the current one.
+                chunk = unsafe { &*(&function.chunk as *const Chunk) };
+                ip = frame.ip;
+            }};
+        }
+        macro_rules! save_ip {
+            () => {
+                self.frame_mut().ip = ip
+            };
+        }
+        macro_rules! fail {
+            ($message:expr) => {{
+                save_ip!();
+                return Err(self.runtime_error($message));
+            }};
+        }
+        macro_rules! read_byte {
+            () => {{
+                let byte = chunk.code[ip];
+                ip += 1;
+                byte
+            }};
+        }
+        macro_rules! read_u16 {
+            () => {
+                u16::from_be_bytes([read_byte!(), read_byte!()])
+            };
+        }
+        // Reads a constant index, over one byte (or three bytes, for the long form of an
+        // instruction), and returns the constant.
+        macro_rules! read_constant {
+            ($long:expr) => {{
+                let index = if $long {
+                    u32::from_be_bytes([0, read_byte!(), read_byte!(), read_byte!()])
+                } else {
+                    read_byte!() as u32
+                };
+                chunk.constants[index as usize]
+            }};
+        }
+        macro_rules! read_name {
+            ($long:expr) => {
+                read_constant!($long).as_object().unwrap()
+            };
+        }
+
+        load_frame!();
+        loop {
+            if self.trace || self.profile.is_some() {
+                save_ip!();
+                if self.trace {
+                    self.trace_ins();
+                }
+                if let Some(profile) = &mut self.profile {
+                    profile.record(&self.frames);
+                }
+            }
+            self.executed += 1;
+            let byte = read_byte!();
+            let Some(opcode) = OpCode::from_byte(byte) else {
+                unreachable!("VM bug. Invalid opcode {byte:#04x}");
+            };
+
+            use OpCode::*;
+            match opcode {
+                Constant | ConstantLong => {
+                    let constant = read_constant!(opcode == ConstantLong);
+                    self.push(constant);
+                }
+                Nil => self.push(Value::NIL),
+                True => self.push(Value::from(true)),
+                False => self.push(Value::from(false)),
+                Pop => {
+                    self.pop();
+                }
+                GetLocal => {
+                    let slot = read_byte!();
+                    let slot_base = self.frame().slot_base;
+                    self.push(self.stack[slot_base + slot as usize]);
+                }
+                SetLocal => {
+                    let slot = read_byte!();
+                    let slot_base = self.frame().slot_base;
+                    self.stack[slot_base + slot as usize] = *self.stack.last().unwrap();
+                }
+                GetUpvalue => {
+                    let index = read_byte!();
+                    let upvalue = self.upvalue(index);
+                    match upvalue.state.get() {
+                        UpvalueState::Open(slot) => self.push(self.stack[slot]),
+                        UpvalueState::Closed(value) => self.push(value),
+                    }
+                }
+                SetUpvalue => {
+                    let index = read_byte!();
+                    let upvalue = self.upvalue(index);
+                    let value = *self.stack.last().unwrap();
+                    match upvalue.state.get() {
+                        UpvalueState::Open(slot) => self.stack[slot] = value,
+                        UpvalueState::Closed(_) => upvalue.state.set(UpvalueState::Closed(value)),
+                    }
+                }
+                DefineGlobal | DefineGlobalLong => {
+                    let name = read_name!(opcode == DefineGlobalLong);
+                    let value = self.pop();
+                    self.globals.insert(name, value);
+                }
+                GetGlobal | GetGlobalLong => {
+                    let name = read_name!(opcode == GetGlobalLong);
+                    match self.globals.get(name) {
+                        Some(&value) => self.push(value),
+                        None => fail!(format!("Undefined variable `{name}`")),
+                    }
+                }
+                SetGlobal | SetGlobalLong => {
+                    let name = read_name!(opcode == SetGlobalLong);
+                    let value = *self.stack.last().unwrap();
+                    match self.globals.get_mut(name) {
+                        Some(global) => *global = value,
+                        None => fail!(format!("Undefined variable `{name}`")),
+                    }
+                }
+                GetProperty | GetPropertyLong => {
+                    let name = read_name!(opcode == GetPropertyLong);
+                    let cache = read_u16!();
+                    save_ip!();
+                    let object = self.instance_at(0)?;
+                    let instance = object.as_instance().unwrap();
+                    let cache = &chunk.caches[cache as usize];
+                    let (field, hit) = find_field(instance, name, cache);
+                    match field {
+                        Some(value) => {
+                            self.stats.property_cache.record(hit);
+                            self.pop(); // The instance.
+                            self.push(value);
+                        }
+                        None => {
+                            let (method, hit) = find_method(instance.class, name, cache);
+                            self.stats.property_cache.record(hit);
+                            self.bind_method(name, method)?;
+                        }
+                    }
+                }
+                SetProperty | SetPropertyLong => {
+                    let name = read_name!(opcode == SetPropertyLong);
+                    save_ip!();
+                    let object = self.instance_at(1)?;
+                    let value = self.pop();
+                    object
+                        .as_instance()
+                        .unwrap()
+                        .fields
+                        .borrow_mut()
+                        .insert(name, value);
+                    self.pop(); // The instance.
                     self.push(value);
                 }
-                Negate => match self.pop() {
-                    Value::Number(number) => self.push(Value::Number(-number)),
-                },
-                Add => arithmetic_binary!(self, +),
-                Subtract => arithmetic_binary!(self, -),
-                Multiply => arithmetic_binary!(self, *),
-                Divide => arithmetic_binary!(self, /),
+                GetSuper | GetSuperLong => {
+                    let name = read_name!(opcode == GetSuperLong);
+                    save_ip!();
+                    let superclass = self.class_at(0, "Superclass must be a class")?;
+                    self.pop();
+                    self.bind_method(name, lookup_method(superclass, name))?;
+                }
+                Equal => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    self.push(Value::from(a == b));
+                }
+                Greater => comparison_binary!(self, ip, >),
+                Less => comparison_binary!(self, ip, <),
+                Negate => {
+                    let value = self.pop();
+                    match value.as_number() {
+                        Some(number) => self.push(Value::from(-number)),
+                        None => fail!(format!(
+                            "Bad type for unary `-` operator: `{}`",
+                            value.type_name()
+                        )),
+                    }
+                }
+                Not => {
+                    let value = self.pop();
+                    self.push(Value::from(value.is_falsy()));
+                }
+                Show => {
+                    let value = self.pop();
+                    let string = self.intern(&value.to_string());
+                    self.push(Value::from(string));
+                }
+                Typeof => {
+                    let value = self.pop();
+                    let string = self.intern(value.type_name());
+                    self.push(Value::from(string));
+                }
+                Add => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    match (a.as_number(), b.as_number()) {
+                        (Some(a), Some(b)) => self.push(Value::from(a + b)),
+                        _ => match (a.as_str(), b.as_str()) {
+                            (Some(a), Some(b)) => {
+                                let string = self.intern(&[a, b].concat());
+                                self.push(Value::from(string));
+                            }
+                            _ => fail!(format!(
+                                "Binary `+` operator can only operate over two numbers or two \
+                                strings. Got types `{}` and `{}`",
+                                a.type_name(),
+                                b.type_name()
+                            )),
+                        },
+                    }
+                }
+                Subtract => arithmetic_binary!(self, ip, -),
+                Multiply => arithmetic_binary!(self, ip, *),
+                Divide => {
+                    if let Some(divisor) = self.peek(0).as_number() {
+                        if divisor == 0.0 {
+                            fail!("Can not divide by zero");
+                        }
+                    }
+                    arithmetic_binary!(self, ip, /)
+                }
+                Jump => {
+                    let offset = read_u16!();
+                    ip += offset as usize;
+                }
+                JumpIfFalse => {
+                    let offset = read_u16!();
+                    if self.stack.last().unwrap().is_falsy() {
+                        ip += offset as usize;
+                    }
+                }
+                Loop => {
+                    let offset = read_u16!();
+                    save_ip!();
+                    self.check_limits()?;
+                    ip -= offset as usize;
+                }
+                Closure | ClosureLong => {
+                    let function = read_constant!(opcode == ClosureLong);
+                    let function = function.as_object().unwrap();
+                    let closure = self.new_closure(function);
+                    self.push(Value::from(closure));
+                }
+                CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                }
+                Call => {
+                    let argc = read_byte!();
+                    save_ip!();
+                    self.check_limits()?;
+                    let callee = self.peek(argc as usize);
+                    self.call_value(callee, argc)?;
+                    load_frame!();
+                }
+                Invoke | InvokeLong => {
+                    let name = read_name!(opcode == InvokeLong);
+                    let argc = read_byte!();
+                    let cache = read_u16!();
+                    save_ip!();
+                    self.check_limits()?;
+                    let object = self.instance_at(argc as usize)?;
+                    let instance = object.as_instance().unwrap();
+                    // A field which holds a function may also be called as a method.
+                    let field = instance.fields.borrow().get(name).copied();
+                    if let Some(field) = field {
+                        let callee_slot = self.stack.len() - argc as usize - 1;
+                        self.stack[callee_slot] = field;
+                        self.call_value(field, argc)?;
+                    } else {
+                        let cache = &chunk.caches[cache as usize];
+                        let (method, hit) = find_method(instance.class, name, cache);
+                        self.stats.invoke_cache.record(hit);
+                        let Some(method) = method else {
+                            return Err(self.undefined_property(name));
+                        };
+                        self.call(method, argc)?;
+                    }
+                    load_frame!();
+                }
+                SuperInvoke | SuperInvokeLong => {
+                    let name = read_name!(opcode == SuperInvokeLong);
+                    let argc = read_byte!();
+                    let cache = read_u16!();
+                    save_ip!();
+                    self.check_limits()?;
+                    let superclass = self.class_at(0, "Superclass must be a class")?;
+                    self.pop();
+                    let cache = &chunk.caches[cache as usize];
+                    let (method, hit) = find_method(superclass, name, cache);
+                    self.stats.invoke_cache.record(hit);
+                    let Some(method) = method else {
+                        return Err(self.undefined_property(name));
+                    };
+                    self.call(method, argc)?;
+                    load_frame!();
+                }
+                Class | ClassLong => {
+                    let name = read_name!(opcode == ClassLong);
+                    let class = self.alloc(ObjectKind::Class(common::Class::new(name)));
+                    self.push(Value::from(class));
+                }
+                Inherit => {
+                    save_ip!();
+                    let superclass = self.class_at(1, "Superclass must be a class")?;
+                    let subclass = self.class_at(0, "Subclass must be a class")?;
+                    self.pop();
+                    let methods = superclass.as_class().unwrap().methods.borrow().clone();
+                    let mut subclass_methods = subclass.as_class().unwrap().methods.borrow_mut();
+                    for (name, &method) in methods.iter() {
+                        subclass_methods.insert(name, method);
+                    }
+                }
+                Method | MethodLong => {
+                    let name = read_name!(opcode == MethodLong);
+                    let method = match self.peek(0).as_object() {
+                        Some(object) if object.as_closure().is_some() => object,
+                        _ => fail!("Method must be a function"),
+                    };
+                    save_ip!();
+                    let class = self.class_at(1, "Methods must be defined in a class")?;
+                    self.pop();
+                    class
+                        .as_class()
+                        .unwrap()
+                        .methods
+                        .borrow_mut()
+                        .insert(name, method);
+                }
+                Print => {
+                    let value = self.pop();
+                    println!("{value}");
+                }
                 Return => {
-                    let val = self.pop();
-                    println!("{val}");
+                    let result = self.pop();
+                    let frame = self.frames.pop().unwrap();
+                    self.close_upvalues(frame.slot_base);
+                    // Discards the callee, its arguments and its local variables.
+                    self.stack.truncate(frame.slot_base);
+                    if self.frames.is_empty() {
+                        return Ok(());
+                    }
+                    self.push(result);
+                    load_frame!();
+                }
+                Throw => {
+                    let exception = self.pop();
+                    save_ip!();
+                    self.throw(exception)?;
+                    load_frame!();
+                }
+            }
+        }
+    }
+
+    /// Calls the given value with the arguments placed on the top of the stack.
+    fn call_value(&mut self, callee: Value, argc: u8) -> Result<()> {
+        if let Some(object) = callee.as_object() {
+            match &object.kind {
+                ObjectKind::Closure(_) => return self.call(object, argc),
+                ObjectKind::BoundMethod(bound) => {
+                    // The receiver takes the place of the callee, so that it is in the slot of
+                    // `this` in the method's frame.
+                    let callee_slot = self.stack.len() - argc as usize - 1;
+                    self.stack[callee_slot] = bound.receiver;
+                    return self.call(bound.method, argc);
+                }
+                ObjectKind::Class(_) => {
+                    let instance = self.alloc(ObjectKind::Instance(Instance::new(object)));
+                    let callee_slot = self.stack.len() - argc as usize - 1;
+                    self.stack[callee_slot] = Value::from(instance);
+                    let init = lookup_method(object, self.init_string);
+                    return match init {
+                        Some(init) => self.call(init, argc),
+                        None => self.check_arity(0, argc),
+                    };
+                }
+                ObjectKind::Native(native) => {
+                    self.check_arity(native.arity, argc)?;
+                    let args_start = self.stack.len() - argc as usize;
+                    let result = (native.function)(&mut self.heap, &self.stack[args_start..])
+                        .map_err(|message| self.native_error(&native.name, message))?;
+                    // Discards the callee and its arguments.
+                    self.stack.truncate(args_start - 1);
+                    self.push(result);
                     return Ok(());
                 }
+                _ => (),
             }
         }
+        Err(self.runtime_error(format!(
+            "Type `{}` is not callable, can only call functions and classes",
+            callee.type_name()
+        )))
+    }
+
+    /// Calls the given closure object by pushing a new call frame.
+    fn call(&mut self, closure: ObjectRef, argc: u8) -> Result<()> {
+        let function = closure.as_closure().unwrap().function;
+        self.check_arity(function.as_function().unwrap().arity, argc)?;
+        if self.frames.len() == FRAMES_MAX {
+            return Err(self.runtime_error("Stack overflow"));
+        }
+        self.frames.push(CallFrame {
+            closure,
+            function,
+            ip: 0,
+            slot_base: self.stack.len() - argc as usize - 1,
+        });
         Ok(())
     }
 
-    fn push(&mut self, value: Value) {
-        if self.stack.len() == self.stack.capacity() {
-            panic!("Stack overflow");
+    fn check_arity(&mut self, arity: u8, argc: u8) -> Result<()> {
+        if arity != argc {
+            return Err(self.runtime_error(format!("Expected {arity} arguments, but got {argc}")));
+        }
+        Ok(())
+    }
+
+    /// Defines a native function as a global variable.
+    fn register_native(&mut self, name: &str, arity: u8, function: NativeFn) {
+        let native = self.alloc(ObjectKind::Native(NativeFunction {
+            name: name.into(),
+            arity,
+            function,
+        }));
+        // The native is kept in the stack so that it is not collected while the name is interned.
+        self.push(Value::from(native));
+        let name = self.intern(name);
+        let native = self.pop();
+        self.globals.insert(name, native);
+    }
+
+    /// Returns the instance which is `distance` slots down from the top of the stack. Reports a
+    /// runtime error if the value is not an instance.
+    fn instance_at(&mut self, distance: usize) -> Result<ObjectRef> {
+        match self.peek(distance).as_object() {
+            Some(object) if object.as_instance().is_some() => Ok(object),
+            _ => Err(self.runtime_error("Only objects (instances of some class) have properties")),
+        }
+    }
+
+    /// Returns the class which is `distance` slots down from the top of the stack. Reports a
+    /// runtime error with the given message if the value is not a class.
+    fn class_at(&mut self, distance: usize, message: &str) -> Result<ObjectRef> {
+        match self.peek(distance).as_object() {
+            Some(object) if object.as_class().is_some() => Ok(object),
+            _ => Err(self.runtime_error(message)),
+        }
+    }
+
+    /// Replaces the instance on the top of the stack with the given method bound to it. Reports a
+    /// runtime error if there is no method (with the given name).
+    fn bind_method(&mut self, name: ObjectRef, method: Option<ObjectRef>) -> Result<()> {
+        let Some(method) = method else {
+            return Err(self.undefined_property(name));
+        };
+        let receiver = self.pop();
+        let bound = self.alloc(ObjectKind::BoundMethod(BoundMethod { receiver, method }));
+        self.push(Value::from(bound));
+        Ok(())
+    }
+
+    /// Creates a closure for the given function, capturing its upvalues from the current frame.
+    fn new_closure(&mut self, function: ObjectRef) -> ObjectRef {
+        let frame = self.frame();
+        let (slot_base, enclosing) = (frame.slot_base, frame.closure);
+        let upvalues = function
+            .as_function()
+            .unwrap()
+            .captures
+            .iter()
+            .map(|capture| {
+                if capture.is_local {
+                    self.capture_upvalue(slot_base + capture.index as usize)
+                } else {
+                    enclosing.as_closure().unwrap().upvalues[capture.index as usize]
+                }
+            })
+            .collect();
+        self.alloc(ObjectKind::Closure(Closure { function, upvalues }))
+    }
+
+    /// Returns the upvalue which refers to the given stack slot, creating it if needed. Hence
+    /// closures which capture the same variable share the same upvalue.
+    fn capture_upvalue(&mut self, slot: usize) -> ObjectRef {
+        match self
+            .open_upvalues
+            .binary_search_by_key(&slot, |&upvalue| open_slot(upvalue))
+        {
+            Ok(index) => self.open_upvalues[index],
+            Err(index) => {
+                let upvalue = self.alloc(ObjectKind::Upvalue(Upvalue {
+                    state: Cell::new(UpvalueState::Open(slot)),
+                }));
+                self.open_upvalues.insert(index, upvalue);
+                upvalue
+            }
+        }
+    }
+
+    /// Closes all the open upvalues which refer to the given stack slot or above it.
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(&upvalue) = self.open_upvalues.last() {
+            let slot = open_slot(upvalue);
+            if slot < last {
+                break;
+            }
+            let value = self.stack[slot];
+            upvalue
+                .as_upvalue()
+                .unwrap()
+                .state
+                .set(UpvalueState::Closed(value));
+            self.open_upvalues.pop();
+        }
+    }
+
+    /// Returns the upvalue with the given index in the current closure.
+    fn upvalue(&self, index: u8) -> &Upvalue {
+        let upvalue = &self.frame().closure.as_closure().unwrap().upvalues[index as usize];
+        upvalue.as_upvalue().unwrap()
+    }
+
+    /// Throws the given exception, unwinding the stack until the innermost exception handler
+    /// which covers the instruction being executed (in its frame). Reports a runtime error if
+    /// there is no such handler.
+    fn throw(&mut self, exception: Value) -> Result<()> {
+        self.check_limits()?;
+        let Some((frame, handler)) = self.find_handler() else {
+            return Err(self.raise(
+                RuntimeErrorKind::Uncaught,
+                format!("Uncaught exception: {exception}"),
+                Vec::new(),
+            ));
+        };
+        self.unwind(frame, handler);
+        self.push(exception);
+        Ok(())
+    }
+
+    /// Throws the given runtime error as an instance of the `Error` class, whose `message` and
+    /// `line` fields describe it. Gives the error back if there is no handler to catch it.
+    fn catch_error(&mut self, error: RuntimeError) -> Result<()> {
+        self.check_limits()?;
+        let Some((frame, handler)) = self.find_handler() else {
+            return Err(Error::RuntimeError(error));
+        };
+        self.unwind(frame, handler);
+        let exception = self.alloc(ObjectKind::Instance(Instance::new(self.error_class)));
+        self.push(Value::from(exception));
+        // Each object is rooted (by the stack or the exception) before the next allocation.
+        let message = self.intern(&error.message);
+        self.push(Value::from(message));
+        let name = self.intern("message");
+        let message = self.pop();
+        let fields = &exception.as_instance().unwrap().fields;
+        fields.borrow_mut().insert(name, message);
+        let name = self.intern("line");
+        fields
+            .borrow_mut()
+            .insert(name, Value::from(error.line as f64));
+        Ok(())
+    }
+
+    /// Returns the innermost exception handler which covers the instruction being executed by
+    /// some frame, alongside with the index of the frame.
+    fn find_handler(&self) -> Option<(usize, Handler)> {
+        self.frames
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(index, frame)| {
+                let chunk = &frame.function.as_function().unwrap().chunk;
+                let offset = frame.ip - 1;
+                let handler = chunk
+                    .handlers
+                    .iter()
+                    .find(|handler| handler.covers(offset))?;
+                Some((index, *handler))
+            })
+    }
+
+    /// Discards the frames above the given one and the stack slots above the height of the given
+    /// handler (closing their upvalues), then jumps to the handler.
+    fn unwind(&mut self, frame: usize, handler: Handler) {
+        self.frames.truncate(frame + 1);
+        let frame = self.frame_mut();
+        frame.ip = handler.target as usize;
+        let height = frame.slot_base + handler.depth as usize;
+        self.close_upvalues(height);
+        self.stack.truncate(height);
+    }
+
+    /// Discards the whole execution state, once the execution is aborted.
+    fn reset(&mut self) {
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+    }
+
+    /// Allocates a new object of the given kind, collecting garbage beforehand if needed.
+    pub(crate) fn alloc(&mut self, kind: ObjectKind) -> ObjectRef {
+        if self.heap.should_collect() {
+            // The objects referenced by the new object may not be reachable from the roots.
+            self.heap.mark_references(&kind);
+            self.collect_garbage();
+        }
+        self.heap.alloc(kind)
+    }
+
+    /// Returns the interned string object for the given string, collecting garbage beforehand if
+    /// needed.
+    pub(crate) fn intern(&mut self, string: &str) -> ObjectRef {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.intern(string)
+    }
+
+    /// Aborts the execution if the instruction budget is exceeded or if it was interrupted. Since
+    /// the execution may only run indefinitely through loops and calls, this is only checked by
+    /// the backward jumps and the calls.
+    fn check_limits(&mut self) -> Result<()> {
+        if let Some(max) = self.max_instructions.filter(|&max| self.executed > max) {
+            return Err(self.raise(
+                RuntimeErrorKind::LimitExceeded,
+                format!("Execution limit exceeded (more than {max} instructions)"),
+                Vec::new(),
+            ));
+        }
+        if self.interrupt.swap(false, Ordering::Relaxed) {
+            return Err(self.raise(
+                RuntimeErrorKind::Interrupted,
+                "Execution interrupted".into(),
+                Vec::new(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Prints the stack contents and the next instruction (with its line) to the standard error.
+    fn trace_ins(&self) {
+        let frame = self.frame();
+        let function = frame.function;
+        let mut out = String::from("          ");
+        for value in &self.stack {
+            write!(out, "[ {value:?} ]").unwrap();
+        }
+        out.push('\n');
+        Disassembler::new(&mut out)
+            .ins(&function.as_function().unwrap().chunk, frame.ip, true)
+            .unwrap();
+        eprint!("{out}");
+    }
+
+    /// Marks the VM roots and performs a garbage collection. Other roots (such as the ones of the
+    /// compiler) must have been marked beforehand.
+    pub(crate) fn collect_garbage(&mut self) {
+        for &value in &self.stack {
+            self.heap.mark_value(value);
+        }
+        for frame in &self.frames {
+            self.heap.mark_object(frame.closure);
+        }
+        for (name, &value) in self.globals.iter() {
+            self.heap.mark_object(name);
+            self.heap.mark_value(value);
+        }
+        for &upvalue in &self.open_upvalues {
+            self.heap.mark_object(upvalue);
         }
+        self.heap.mark_object(self.init_string);
+        self.heap.mark_object(self.error_class);
+        self.heap.collect();
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn push(&mut self, value: Value) {
         self.stack.push(value);
     }
 
     fn pop(&mut self) -> Value {
         self.stack.pop().unwrap()
     }
+
+    /// Returns the value which is `distance` slots down from the top of the stack.
+    fn peek(&self, distance: usize) -> Value {
+        self.stack[self.stack.len() - 1 - distance]
+    }
+
+    /// Creates a new runtime error for an access to the given property, which doesn't exist.
+    fn undefined_property(&mut self, name: ObjectRef) -> Error {
+        self.runtime_error(format!("Undefined property `{name}`"))
+    }
+
+    /// Creates a new runtime error, attributing it to the line and span of the current instruction.
+    fn runtime_error(&mut self, message: impl Into<String>) -> Error {
+        self.raise(RuntimeErrorKind::Failure, message.into(), Vec::new())
+    }
+
+    /// Creates a new runtime error raised by the given native function, which is the innermost
+    /// entry of the stack trace. See `runtime_error`.
+    fn native_error(&mut self, native: &str, message: String) -> Error {
+        let trace = vec![TraceFrame {
+            function: native.into(),
+            line: None,
+        }];
+        self.raise(RuntimeErrorKind::Failure, message, trace)
+    }
+
+    /// Creates a new runtime error, completing the given stack trace with the active call frames.
+    /// The state of the VM is left as is, so that the error may still be caught (see `run`).
+    fn raise(&self, kind: RuntimeErrorKind, message: String, mut trace: Vec<TraceFrame>) -> Error {
+        trace.extend(self.frames.iter().rev().map(|frame| {
+            let function = frame.function.as_function().unwrap();
+            TraceFrame {
+                function: function.name().into(),
+                line: Some(function.chunk.line_at(frame.ip - 1)),
+            }
+        }));
+        let line = trace.iter().find_map(|frame| frame.line).unwrap_or(0);
+        let span = self.frames.last().map(|frame| {
+            let function = frame.function.as_function().unwrap();
+            function.chunk.span_at(frame.ip - 1)
+        });
+        Error::RuntimeError(RuntimeError {
+            kind,
+            message,
+            line,
+            span,
+            trace,
+        })
+    }
+}
+
+impl Default for Vm {
+    fn default() -> Vm {
+        Vm::new()
+    }
+}
+
+/// Returns the method of the given class with the given name, if any.
+fn lookup_method(class: ObjectRef, name: ObjectRef) -> Option<ObjectRef> {
+    class
+        .as_class()
+        .unwrap()
+        .methods
+        .borrow()
+        .get(name)
+        .copied()
+}
+
+/// Looks up the field of the given instance with the given name, through the given inline cache.
+/// Returns the field's value (if any) and whether it was found at the cached entry.
+fn find_field(
+    instance: &Instance,
+    name: ObjectRef,
+    cache: &Cell<Option<InlineCache>>,
+) -> (Option<Value>, bool) {
+    let fields = instance.fields.borrow();
+    if let Some(InlineCache {
+        class,
+        property: CachedProperty::Field(entry),
+    }) = cache.get()
+    {
+        if class == instance.class {
+            if let Some(&value) = fields.get_at(entry, name) {
+                return (Some(value), true);
+            }
+        }
+    }
+    let entry = fields.index_of(name);
+    if let Some(entry) = entry {
+        cache.set(Some(InlineCache {
+            class: instance.class,
+            property: CachedProperty::Field(entry),
+        }));
+    }
+    (
+        entry.and_then(|entry| fields.get_at(entry, name).copied()),
+        false,
+    )
+}
+
+/// Looks up the method of the given class with the given name, through the given inline cache.
+/// Returns the method (if any) and whether it was found in the cache.
+fn find_method(
+    class: ObjectRef,
+    name: ObjectRef,
+    cache: &Cell<Option<InlineCache>>,
+) -> (Option<ObjectRef>, bool) {
+    match cache.get() {
+        Some(InlineCache {
+            class: cached_class,
+            property: CachedProperty::Method(method),
+        }) if cached_class == class => (Some(method), true),
+        _ => {
+            let method = lookup_method(class, name);
+            if let Some(method) = method {
+                cache.set(Some(InlineCache {
+                    class,
+                    property: CachedProperty::Method(method),
+                }));
+            }
+            (method, false)
+        }
+    }
+}
+
+/// Returns the stack slot referred to by the given open upvalue.
+fn open_slot(upvalue: ObjectRef) -> usize {
+    match upvalue.as_upvalue().unwrap().state.get() {
+        UpvalueState::Open(slot) => slot,
+        UpvalueState::Closed(_) => unreachable!("VM bug. Expected open upvalue"),
+    }
 }
 
 macro_rules! arithmetic_binary {
-    ($self:expr, $op:tt) => {{
+    ($self:expr, $ip:expr, $op:tt) => {{
         let b = $self.pop();
         let a = $self.pop();
-        use Value::*;
-        let out = match (a, b) {
-            (Number(a), Number(b)) => Number(a $op b),
-        };
-        $self.push(out);
+        match (a.as_number(), b.as_number()) {
+            (Some(a), Some(b)) => $self.push(Value::from(a $op b)),
+            _ => {
+                $self.frame_mut().ip = $ip;
+                return Err($self.runtime_error(format!(
+                    "Binary `{}` operator can only operate over two numbers. \
+                    Got types `{}` and `{}`",
+                    stringify!($op),
+                    a.type_name(),
+                    b.type_name()
+                )));
+            }
+        }
     }}
 }
 use arithmetic_binary;
+
+macro_rules! comparison_binary {
+    ($self:expr, $ip:expr, $op:tt) => {{
+        let b = $self.pop();
+        let a = $self.pop();
+        match (a.as_number(), b.as_number()) {
+            (Some(a), Some(b)) => $self.push(Value::from(a $op b)),
+            _ => match (a.as_str(), b.as_str()) {
+                (Some(a), Some(b)) => $self.push(Value::from(a $op b)),
+                _ => {
+                    $self.frame_mut().ip = $ip;
+                    return Err($self.runtime_error(format!(
+                        "Binary `{}` operator can only compare two numbers or two strings. \
+                        Got types `{}` and `{}`",
+                        stringify!($op),
+                        a.type_name(),
+                        b.type_name()
+                    )));
+                }
+            },
+        }
+    }}
+}
+use comparison_binary;
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::*;
+    use crate::pipeline::interpret_in;
+
+    /// Runs the given source, returning its runtime error (if any).
+    fn run(vm: &mut Vm, source: &str) -> Option<RuntimeError> {
+        match interpret_in(vm, source) {
+            Ok(()) => None,
+            Err(Error::RuntimeError(error)) => Some(error),
+            Err(error) => panic!("Unexpected error: {error}"),
+        }
+    }
+
+    /// Runs the given source, which must not fail, and returns the value of the given global.
+    fn run_global(source: &str, name: &str) -> Option<LoxValue> {
+        let mut vm = Vm::new();
+        assert_eq!(run(&mut vm, source), None);
+        vm.global(name)
+    }
+
+    #[test]
+    fn unwinds_through_call_frames() {
+        let source = "
+            var log = \"\";
+            fun c() { var z = 3; throw 10; }
+            fun b() { var y = 2; c(); log = \"unreachable\"; }
+            fun a() { var x = 1; try { var w = 0; b(); } catch (e) { return x + e; } }
+            var result = a();
+            var after = a() + result;
+        ";
+        assert_eq!(run_global(source, "result"), Some(LoxValue::Number(11.0)));
+        assert_eq!(run_global(source, "after"), Some(LoxValue::Number(22.0)));
+        assert_eq!(run_global(source, "log"), Some(LoxValue::String("".into())));
+    }
+
+    #[test]
+    fn closes_upvalues_when_unwinding() {
+        let source = "
+            var get;
+            fun f() {
+                var x = \"callee\";
+                fun g() { return x; }
+                get = g;
+                throw nil;
+            }
+            fun test() {
+                var get_local;
+                try {
+                    var y = \"local\";
+                    fun h() { return y; }
+                    get_local = h;
+                    f();
+                } catch (e) {
+                    // Overwrites the stack slots of the unwound locals.
+                    var a = \"a\"; var b = \"b\"; var c = \"c\";
+                }
+                return get() + \" \" + get_local();
+            }
+            var result = test();
+        ";
+        assert_eq!(
+            run_global(source, "result"),
+            Some(LoxValue::String("callee local".into()))
+        );
+    }
+
+    #[test]
+    fn catches_inside_loops() {
+        let source = "
+            var caught = 0;
+            for (var i = 0; i < 5; i = i + 1) {
+                var twice = i * 2;
+                try {
+                    if (i > 1) throw twice;
+                } catch (e) {
+                    caught = caught + e;
+                }
+            }
+        ";
+        assert_eq!(run_global(source, "caught"), Some(LoxValue::Number(18.0)));
+    }
+
+    #[test]
+    fn catches_in_the_innermost_try() {
+        let source = "
+            var log = \"\";
+            try {
+                try {
+                    throw \"inner\";
+                } catch (e) {
+                    log = log + e;
+                }
+                try {} catch (e) { log = log + \" unreachable\"; }
+                throw \" outer\";
+            } catch (e) {
+                log = log + e;
+            }
+        ";
+        assert_eq!(
+            run_global(source, "log"),
+            Some(LoxValue::String("inner outer".into()))
+        );
+    }
+
+    #[test]
+    fn rethrows_from_catch_blocks() {
+        let source = "
+            fun f() {
+                try { throw \"first\"; } catch (e) { throw e + \" again\"; }
+            }
+            var result;
+            try { f(); } catch (e) { result = e; }
+        ";
+        assert_eq!(
+            run_global(source, "result"),
+            Some(LoxValue::String("first again".into()))
+        );
+
+        let error = run(&mut Vm::new(), "try { throw 1; } catch (e) { throw e; }").unwrap();
+        assert_eq!(error.kind, RuntimeErrorKind::Uncaught);
+        assert_eq!(error.message, "Uncaught exception: 1");
+    }
+
+    #[test]
+    fn catches_runtime_errors() {
+        let source = "
+            var message;
+            var line;
+            try {
+                1 / 0;
+            } catch (e) {
+                message = e.message;
+                line = e.line;
+            }
+        ";
+        assert_eq!(
+            run_global(source, "message"),
+            Some(LoxValue::String("Can not divide by zero".into()))
+        );
+        assert_eq!(run_global(source, "line"), Some(LoxValue::Number(5.0)));
+    }
+
+    #[test]
+    fn doesnt_catch_budget_errors() {
+        let mut vm = Vm::with_options(VmOptions {
+            max_instructions: Some(1000),
+            ..VmOptions::default()
+        });
+        let source = "
+            var caught = false;
+            try { while (true) {} } catch (e) { caught = true; }
+        ";
+        let error = run(&mut vm, source).unwrap();
+        assert_eq!(error.kind, RuntimeErrorKind::LimitExceeded);
+        assert_eq!(vm.global("caught"), Some(LoxValue::Bool(false)));
+    }
+
+    #[test]
+    fn stops_infinite_loops() {
+        let mut vm = Vm::with_options(VmOptions {
+            max_instructions: Some(10_000),
+            ..VmOptions::default()
+        });
+        let error = run(&mut vm, "var i = 0;\nwhile (true) { i = i + 1; }").unwrap();
+        assert_eq!(error.kind, RuntimeErrorKind::LimitExceeded);
+        assert_eq!(
+            error.message,
+            "Execution limit exceeded (more than 10000 instructions)"
+        );
+        assert_eq!(error.line, 2);
+        // The budget applies to each interpretation.
+        assert_eq!(run(&mut vm, "var j = i;"), None);
+
+        let source =
+            "fun f(n) { if (n > 0) { f(n - 1); f(n - 1); } }\ntry { f(100); } catch (e) {}";
+        let error = run(&mut vm, source).unwrap();
+        assert_eq!(error.kind, RuntimeErrorKind::LimitExceeded);
+    }
+
+    #[test]
+    fn stops_once_interrupted() {
+        let mut vm = Vm::new();
+        let interrupt = vm.interrupt_flag();
+        let source = "
+            var caught = false;
+            try { while (true) {} } catch (e) { caught = true; }
+        ";
+        let interrupting = thread::spawn({
+            let interrupt = Arc::clone(&interrupt);
+            move || {
+                thread::sleep(Duration::from_millis(10));
+                interrupt.store(true, Ordering::Relaxed);
+            }
+        });
+        let error = run(&mut vm, source).unwrap();
+        interrupting.join().unwrap();
+        assert_eq!(error.kind, RuntimeErrorKind::Interrupted);
+        assert_eq!(error.message, "Execution interrupted");
+        assert_eq!(vm.global("caught"), Some(LoxValue::Bool(false)));
+        // The flag is cleared once the execution is aborted.
+        assert!(!interrupt.load(Ordering::Relaxed));
+    }
+}
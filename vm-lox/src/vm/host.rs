@@ -0,0 +1,342 @@
+use std::{
+    cell::RefCell,
+    fmt::{self, Display},
+};
+
+use crate::{
+    common::{Heap, Unpacked, Value},
+    vm::Vm,
+};
+
+/// A Lox value owned by the host program, through which values are exchanged with a virtual
+/// machine (see `Vm::global`, `Vm::set_global` and `Vm::define_native`).
+///
+/// Unlike the values of the machine, it holds no reference to its heap, hence it may be kept
+/// across interpretations (and garbage collections). The objects other than strings (such as
+/// functions and instances) are therefore only represented by their description, and can't be
+/// passed back to the machine.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoxValue {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    /// Any other object, e.g. a function or an instance. It can't be passed back to the machine
+    /// (see `Vm::set_global`).
+    Object {
+        type_name: &'static str,
+        /// The object as printed by Lox, e.g. `<fun add>`.
+        description: String,
+    },
+}
+
+impl LoxValue {
+    /// Returns the canonical type name (see `Value::type_name`).
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            LoxValue::Nil => "nil",
+            LoxValue::Bool(_) => "boolean",
+            LoxValue::Number(_) => "number",
+            LoxValue::String(_) => "string",
+            LoxValue::Object { type_name, .. } => type_name,
+        }
+    }
+
+    /// Checks if the value is nil.
+    pub fn is_nil(&self) -> bool {
+        matches!(self, LoxValue::Nil)
+    }
+
+    /// Returns the underlying boolean if the value is a boolean. Otherwise None.
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            LoxValue::Bool(boolean) => Some(*boolean),
+            _ => None,
+        }
+    }
+
+    /// Returns the underlying number if the value is a number. Otherwise None.
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            LoxValue::Number(number) => Some(*number),
+            _ => None,
+        }
+    }
+
+    /// Returns the underlying string slice if the value is a string. Otherwise None.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            LoxValue::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    /// Copies the given value of the machine.
+    pub(crate) fn from_value(value: Value) -> LoxValue {
+        match value.unpack() {
+            Unpacked::Nil => LoxValue::Nil,
+            Unpacked::Bool(boolean) => LoxValue::Bool(boolean),
+            Unpacked::Number(number) => LoxValue::Number(number),
+            Unpacked::Object(object) => match object.as_str() {
+                Some(string) => LoxValue::String(string.into()),
+                None => LoxValue::Object {
+                    type_name: object.type_name(),
+                    description: object.to_string(),
+                },
+            },
+        }
+    }
+
+    /// Converts the value into a value of the machine, interning its string (if any) in the given
+    /// heap. The string is not rooted, hence no garbage must be collected before it is. Returns
+    /// None if the value is an object other than a string, since it doesn't refer to the object.
+    pub(crate) fn to_value(&self, heap: &mut Heap) -> Option<Value> {
+        Some(match self {
+            LoxValue::Nil => Value::NIL,
+            LoxValue::Bool(boolean) => Value::from(*boolean),
+            LoxValue::Number(number) => Value::from(*number),
+            LoxValue::String(string) => Value::from(heap.intern(string)),
+            LoxValue::Object { .. } => return None,
+        })
+    }
+}
+
+/// Writes the value as printed by Lox.
+impl Display for LoxValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoxValue::Nil => f.write_str("nil"),
+            LoxValue::Bool(boolean) => Display::fmt(boolean, f),
+            LoxValue::Number(number) => Display::fmt(&Value::from(*number), f),
+            LoxValue::String(string) => f.write_str(string),
+            LoxValue::Object { description, .. } => f.write_str(description),
+        }
+    }
+}
+
+impl From<()> for LoxValue {
+    fn from(_: ()) -> LoxValue {
+        LoxValue::Nil
+    }
+}
+
+impl From<bool> for LoxValue {
+    fn from(boolean: bool) -> LoxValue {
+        LoxValue::Bool(boolean)
+    }
+}
+
+impl From<f64> for LoxValue {
+    fn from(number: f64) -> LoxValue {
+        LoxValue::Number(number)
+    }
+}
+
+impl From<&str> for LoxValue {
+    fn from(string: &str) -> LoxValue {
+        LoxValue::String(string.into())
+    }
+}
+
+impl From<String> for LoxValue {
+    fn from(string: String) -> LoxValue {
+        LoxValue::String(string)
+    }
+}
+
+/// Converts None into nil.
+impl<T: Into<LoxValue>> From<Option<T>> for LoxValue {
+    fn from(option: Option<T>) -> LoxValue {
+        option.map_or(LoxValue::Nil, Into::into)
+    }
+}
+
+/// The conversions into Rust types fail (giving the value back) if the value has another type.
+impl TryFrom<LoxValue> for bool {
+    type Error = LoxValue;
+
+    fn try_from(value: LoxValue) -> Result<bool, LoxValue> {
+        value.as_bool().ok_or(value)
+    }
+}
+
+impl TryFrom<LoxValue> for f64 {
+    type Error = LoxValue;
+
+    fn try_from(value: LoxValue) -> Result<f64, LoxValue> {
+        value.as_number().ok_or(value)
+    }
+}
+
+impl TryFrom<LoxValue> for String {
+    type Error = LoxValue;
+
+    fn try_from(value: LoxValue) -> Result<String, LoxValue> {
+        match value {
+            LoxValue::String(string) => Ok(string),
+            other => Err(other),
+        }
+    }
+}
+
+/// The interface through which the host program accesses the global variables of the machine.
+impl Vm {
+    /// Returns the value of the given global variable, if it is defined.
+    pub fn global(&self, name: &str) -> Option<LoxValue> {
+        // A name which was never interned can't be the name of a global.
+        let name = self.heap.find_interned(name)?;
+        self.globals.get(name).copied().map(LoxValue::from_value)
+    }
+
+    /// Defines (or assigns) the given global variable. Fails (giving the value back) if the value
+    /// is an object other than a string (see `LoxValue::Object`), leaving the variable as is.
+    pub fn set_global(&mut self, name: &str, value: impl Into<LoxValue>) -> Result<(), LoxValue> {
+        let value = value.into();
+        if matches!(value, LoxValue::Object { .. }) {
+            return Err(value);
+        }
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        let value = value.to_value(&mut self.heap).unwrap();
+        // The value is kept in the stack so that it is not collected while the name is interned.
+        self.push(value);
+        let name = self.intern(name);
+        let value = self.pop();
+        self.globals.insert(name, value);
+        Ok(())
+    }
+
+    /// Removes the given global variable. Returns true if it was defined.
+    pub fn remove_global(&mut self, name: &str) -> bool {
+        match self.heap.find_interned(name) {
+            Some(name) => self.globals.remove(name),
+            None => false,
+        }
+    }
+
+    /// Defines a native function as a global variable, implemented by the given Rust function (or
+    /// closure). The function is called with exactly `arity` arguments, and its error message (if
+    /// any) is reported as a runtime error. Returning an object other than a string (see
+    /// `LoxValue::Object`) is also reported as a runtime error.
+    ///
+    /// Since the function may only be called by the machine, which it can't access, it may mutate
+    /// its captured state.
+    pub fn define_native(
+        &mut self,
+        name: &str,
+        arity: u8,
+        function: impl FnMut(&[LoxValue]) -> Result<LoxValue, String> + 'static,
+    ) {
+        let function = RefCell::new(function);
+        let native = move |heap: &mut Heap, args: &[Value]| {
+            let args: Vec<_> = args.iter().copied().map(LoxValue::from_value).collect();
+            let result = (function.borrow_mut())(&args)?;
+            result.to_value(heap).ok_or_else(|| {
+                format!(
+                    "Can't return a `{}` from a native function",
+                    result.type_name()
+                )
+            })
+        };
+        self.register_native(name, arity, Box::new(native));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use super::*;
+    use crate::pipeline::{interpret_in, Error};
+
+    /// Runs the given source, returning the message of its runtime error (if any).
+    fn run(vm: &mut Vm, source: &str) -> Option<String> {
+        match interpret_in(vm, source) {
+            Ok(()) => None,
+            Err(Error::RuntimeError(error)) => Some(error.message),
+            Err(error) => panic!("Unexpected error: {error}"),
+        }
+    }
+
+    #[test]
+    fn reads_globals() {
+        let mut vm = Vm::new();
+        run(
+            &mut vm,
+            "var n = 1.5; var s = \"a\" + \"b\"; var b = true; var z; fun f() {}",
+        );
+        assert_eq!(vm.global("n"), Some(LoxValue::Number(1.5)));
+        assert_eq!(vm.global("s"), Some(LoxValue::String("ab".into())));
+        assert_eq!(vm.global("b"), Some(LoxValue::Bool(true)));
+        assert_eq!(vm.global("z"), Some(LoxValue::Nil));
+        let f = vm.global("f").unwrap();
+        assert_eq!(
+            (f.type_name(), f.to_string()),
+            ("function", "<fun f>".into())
+        );
+        // A name which was interned, and one which wasn't.
+        assert_eq!(vm.global("ab"), None);
+        assert_eq!(vm.global("undefined"), None);
+    }
+
+    #[test]
+    fn sets_globals() {
+        let mut vm = Vm::new();
+        vm.set_global("x", 2.0).unwrap();
+        vm.set_global("name", "lox").unwrap();
+        run(&mut vm, "var y = x * 2; name = name + \"!\";");
+        assert_eq!(vm.global("y"), Some(4.0.into()));
+        assert_eq!(vm.global("name"), Some("lox!".into()));
+        vm.set_global("y", None::<f64>).unwrap();
+        assert_eq!(vm.global("y"), Some(LoxValue::Nil));
+    }
+
+    #[test]
+    fn rejects_objects_other_than_strings() {
+        let mut vm = Vm::new();
+        run(&mut vm, "fun f() { return 1; } var g = 2;");
+        let f = vm.global("f").unwrap();
+        assert_eq!(vm.set_global("g", f.clone()), Err(f.clone()));
+        assert_eq!(vm.global("g"), Some(2.0.into()));
+        assert_eq!(vm.global("f").unwrap().type_name(), "function");
+        assert_eq!(run(&mut vm, "g = f();"), None);
+
+        vm.define_native("leak", 0, move |_| Ok(f.clone()));
+        assert_eq!(
+            run(&mut vm, "leak();").unwrap(),
+            "Can't return a `function` from a native function"
+        );
+    }
+
+    #[test]
+    fn removes_globals() {
+        let mut vm = Vm::new();
+        run(&mut vm, "var x = 1;");
+        assert!(vm.remove_global("x"));
+        assert!(!vm.remove_global("x"));
+        assert!(!vm.remove_global("never"));
+        assert_eq!(vm.global("x"), None);
+        assert_eq!(run(&mut vm, "print x;").unwrap(), "Undefined variable `x`");
+    }
+
+    #[test]
+    fn keeps_the_state_of_natives() {
+        let mut vm = Vm::new();
+        let total = Rc::new(Cell::new(0.0));
+        let state = Rc::clone(&total);
+        vm.define_native("add", 1, move |args| {
+            let number = args[0].as_number().ok_or("Expected a number")?;
+            state.set(state.get() + number);
+            Ok(state.get().into())
+        });
+        run(&mut vm, "var a = add(1); var b = add(2);");
+        run(&mut vm, "var c = add(3);");
+        assert_eq!(vm.global("a"), Some(1.0.into()));
+        assert_eq!(vm.global("b"), Some(3.0.into()));
+        assert_eq!(vm.global("c"), Some(6.0.into()));
+        assert_eq!(total.get(), 6.0);
+        assert_eq!(run(&mut vm, "add(\"x\");").unwrap(), "Expected a number");
+        assert_eq!(total.get(), 6.0);
+    }
+}
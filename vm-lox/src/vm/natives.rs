@@ -0,0 +1,12 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::common::{Heap, Value};
+
+/// Returns the number of seconds elapsed since the Unix epoch.
+pub fn clock(_: &mut Heap, _: &[Value]) -> Result<Value, String> {
+    let since_the_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|error| error.to_string())?
+        .as_secs_f64();
+    Ok(Value::from(since_the_epoch))
+}
@@ -0,0 +1,369 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    fmt::{self, Display},
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    common::{ObjectRef, OpCode},
+    vm::CallFrame,
+};
+
+/// The index of the root of the call tree, which stands for no function at all.
+const ROOT: usize = 0;
+
+/// The execution profile of a virtual machine, accumulated over all of its interpretations: the
+/// number of executed instructions per opcode, and the calls, executed instructions and wall time
+/// of each function. The executed instructions are also recorded per call stack, which may be
+/// written as folded stacks (see `Profile::write_folded`).
+///
+/// The functions are identified by their name and the line they start at, so that the functions
+/// compiled from the same source (e.g. by successive runs of a REPL) share their profile.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    /// The number of executed instructions, indexed by opcode.
+    opcodes: Vec<u64>,
+    functions: Vec<FunctionProfile>,
+    /// The indexes of the profiles of the functions starting at each line. The function objects
+    /// themselves aren't referenced, so that profiling doesn't keep them alive.
+    function_indexes: HashMap<u32, Vec<usize>>,
+    /// The call tree, whose nodes are the distinct call stacks.
+    nodes: Vec<StackNode>,
+    /// The index of the node of each (parent node, function) pair.
+    children: HashMap<(usize, usize), usize>,
+    /// The profiled call frames, which mirror the frames of the VM.
+    stack: Vec<Activation>,
+    /// The instant from which the time is charged to the function on top of the stack.
+    charged_until: Instant,
+}
+
+/// The profile of a function.
+#[derive(Debug, Clone)]
+pub struct FunctionProfile {
+    pub name: String,
+    /// The line of the first instruction of the function.
+    pub line: u32,
+    pub calls: u64,
+    /// The number of instructions executed by the function itself.
+    pub instructions: u64,
+    /// The wall time spent in the function, including the functions it called. The time of the
+    /// recursive calls is only counted once.
+    pub inclusive: Duration,
+    /// The wall time spent in the function itself (including the native functions it called).
+    pub exclusive: Duration,
+    /// The number of ongoing calls of the function.
+    active: u32,
+}
+
+#[derive(Debug, Clone)]
+struct StackNode {
+    parent: usize,
+    /// The index of the profile of the function on top of the call stack.
+    function: usize,
+    /// The number of instructions executed with this call stack.
+    instructions: u64,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Activation {
+    node: usize,
+    entered: Instant,
+}
+
+impl Profile {
+    pub(super) fn new() -> Profile {
+        Profile {
+            opcodes: vec![0; 256],
+            functions: Vec::new(),
+            function_indexes: HashMap::new(),
+            nodes: vec![StackNode {
+                parent: ROOT,
+                function: usize::MAX,
+                instructions: 0,
+            }],
+            children: HashMap::new(),
+            stack: Vec::new(),
+            charged_until: Instant::now(),
+        }
+    }
+
+    /// Returns the function profiles, in no particular order.
+    pub fn functions(&self) -> &[FunctionProfile] {
+        &self.functions
+    }
+
+    /// Returns the total number of executed instructions.
+    pub fn instructions(&self) -> u64 {
+        self.opcodes.iter().sum()
+    }
+
+    /// Writes the executed instructions per call stack in the folded stacks format, as expected
+    /// by the flame graph tools (such as `flamegraph.pl` or `inferno-flamegraph`). Each line holds
+    /// the functions of a call stack (from the outermost one) separated by semicolons, followed by
+    /// the number of instructions executed with this call stack, as in:
+    ///
+    /// ```text
+    /// <script>:1;fib:1 4523
+    /// ```
+    ///
+    /// Unlike a sampling profiler, the weights are exact instruction counts rather than time.
+    pub fn write_folded(&self, out: &mut impl Write) -> io::Result<()> {
+        let mut path = Vec::new();
+        for node in self.nodes.iter().skip(1) {
+            if node.instructions == 0 {
+                continue;
+            }
+            path.clear();
+            let mut current = node;
+            while current.function != usize::MAX {
+                path.push(self.functions[current.function].label());
+                current = &self.nodes[current.parent];
+            }
+            path.reverse();
+            writeln!(out, "{} {}", path.join(";"), node.instructions)?;
+        }
+        Ok(())
+    }
+
+    /// Records the instruction about to be executed by the top frame of the given ones.
+    pub(super) fn record(&mut self, frames: &[CallFrame]) {
+        // An instruction pushes at most one frame, but may pop many of them (when an exception
+        // is thrown), hence comparing the heights is enough to detect the calls and returns.
+        if frames.len() != self.stack.len() {
+            self.sync(frames);
+        }
+        let frame = frames.last().unwrap();
+        let byte = frame.function.as_function().unwrap().chunk.code[frame.ip];
+        self.opcodes[byte as usize] += 1;
+        let node = &mut self.nodes[self.stack.last().unwrap().node];
+        node.instructions += 1;
+        self.functions[node.function].instructions += 1;
+    }
+
+    /// Updates the profiled stack to match the given frames, which differ at most by the frames
+    /// pushed since the last update (and the ones popped).
+    pub(super) fn sync(&mut self, frames: &[CallFrame]) {
+        let now = Instant::now();
+        if let Some(top) = self.stack.last() {
+            let function = self.nodes[top.node].function;
+            self.functions[function].exclusive += now - self.charged_until;
+        }
+        self.charged_until = now;
+
+        while self.stack.len() > frames.len() {
+            let activation = self.stack.pop().unwrap();
+            let function = &mut self.functions[self.nodes[activation.node].function];
+            function.active -= 1;
+            if function.active == 0 {
+                function.inclusive += now - activation.entered;
+            }
+        }
+        for frame in &frames[self.stack.len()..] {
+            let function = self.function_index(frame.function);
+            let parent = self.stack.last().map_or(ROOT, |activation| activation.node);
+            let node = *self.children.entry((parent, function)).or_insert_with(|| {
+                self.nodes.push(StackNode {
+                    parent,
+                    function,
+                    instructions: 0,
+                });
+                self.nodes.len() - 1
+            });
+            let profile = &mut self.functions[function];
+            profile.calls += 1;
+            profile.active += 1;
+            self.stack.push(Activation { node, entered: now });
+        }
+    }
+
+    /// Returns the index of the profile of the given function object, adding one if needed.
+    fn function_index(&mut self, object: ObjectRef) -> usize {
+        let function = object.as_function().unwrap();
+        let line = function.chunk.lines.first().map_or(0, |run| run.line);
+        let indexes = self.function_indexes.entry(line).or_default();
+        // There is usually a single function per line, hence the names are compared linearly.
+        if let Some(&index) = indexes
+            .iter()
+            .find(|&&index| self.functions[index].name == function.name())
+        {
+            return index;
+        }
+        self.functions.push(FunctionProfile {
+            name: function.name().into(),
+            line,
+            calls: 0,
+            instructions: 0,
+            inclusive: Duration::ZERO,
+            exclusive: Duration::ZERO,
+            active: 0,
+        });
+        indexes.push(self.functions.len() - 1);
+        self.functions.len() - 1
+    }
+}
+
+impl FunctionProfile {
+    /// Returns the name of the function followed by its line, as in `fib:1`.
+    pub fn label(&self) -> String {
+        format!("{}:{}", self.name, self.line)
+    }
+}
+
+/// Writes the profile as two tables: the executed instructions per opcode, then the functions
+/// sorted by their exclusive time, as in:
+///
+/// ```text
+/// opcode                  count    share
+/// OP_GET_LOCAL          1617000    28.1%
+/// ...
+///
+/// function        calls   instructions   inclusive (ms)   exclusive (ms)
+/// fib:1          242785        5502839          510.921          509.870
+/// <script>:1          1             17          511.003            0.082
+/// ```
+impl Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.instructions();
+        let mut opcodes: Vec<_> = (0..=u8::MAX)
+            .filter_map(|byte| Some((OpCode::from_byte(byte)?, self.opcodes[byte as usize])))
+            .filter(|&(_, count)| count > 0)
+            .collect();
+        opcodes.sort_by_key(|&(_, count)| Reverse(count));
+        writeln!(f, "{:<16} {:>12} {:>8}", "opcode", "count", "share")?;
+        for (opcode, count) in opcodes {
+            let share = count as f64 / total as f64 * 100.0;
+            writeln!(f, "{:<16} {count:>12} {share:>7.1}%", opcode.name())?;
+        }
+
+        let mut functions: Vec<_> = self.functions.iter().collect();
+        functions.sort_by_key(|function| Reverse(function.exclusive));
+        let labels: Vec<_> = functions.iter().map(|function| function.label()).collect();
+        let width = labels.iter().map(String::len).max().unwrap_or(0).max(8);
+        writeln!(
+            f,
+            "\n{:<width$} {:>10} {:>14} {:>16} {:>16}",
+            "function", "calls", "instructions", "inclusive (ms)", "exclusive (ms)"
+        )?;
+        for (function, label) in functions.iter().zip(&labels) {
+            writeln!(
+                f,
+                "{label:<width$} {:>10} {:>14} {:>16.3} {:>16.3}",
+                function.calls,
+                function.instructions,
+                function.inclusive.as_secs_f64() * 1000.0,
+                function.exclusive.as_secs_f64() * 1000.0
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        pipeline::interpret_in,
+        vm::{Vm, VmOptions},
+    };
+
+    const FIB: &str = "
+fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+fib(4);
+";
+
+    /// Returns a VM which profiles its execution, and collects garbage before every allocation.
+    fn profiled_vm() -> Vm {
+        Vm::with_options(VmOptions {
+            profile: true,
+            stress_gc: true,
+            ..VmOptions::default()
+        })
+    }
+
+    #[test]
+    fn profiles_functions() {
+        let mut vm = profiled_vm();
+        interpret_in(&mut vm, FIB).unwrap();
+        // The functions of a second compilation of the same source share the profiles.
+        interpret_in(&mut vm, FIB).unwrap();
+        let profile = vm.profile().unwrap();
+        let mut functions: Vec<_> = profile
+            .functions()
+            .iter()
+            .map(|function| (function.label(), function.calls))
+            .collect();
+        functions.sort();
+        assert_eq!(functions, [("<script>:2".into(), 2), ("fib:2".into(), 18)]);
+        let instructions: u64 = profile
+            .functions()
+            .iter()
+            .map(|function| function.instructions)
+            .sum();
+        assert_eq!(instructions, profile.instructions());
+    }
+
+    #[test]
+    fn writes_folded_stacks() {
+        let mut vm = profiled_vm();
+        interpret_in(&mut vm, FIB).unwrap();
+        let profile = vm.profile().unwrap();
+        let mut folded = Vec::new();
+        profile.write_folded(&mut folded).unwrap();
+        let folded = String::from_utf8(folded).unwrap();
+        let mut stacks = Vec::new();
+        let mut instructions = 0;
+        for line in folded.lines() {
+            let (stack, count) = line.rsplit_once(' ').unwrap();
+            stacks.push(stack);
+            instructions += count.parse::<u64>().unwrap();
+        }
+        stacks.sort();
+        assert_eq!(
+            stacks,
+            [
+                "<script>:2",
+                "<script>:2;fib:2",
+                "<script>:2;fib:2;fib:2",
+                "<script>:2;fib:2;fib:2;fib:2",
+                "<script>:2;fib:2;fib:2;fib:2;fib:2",
+            ]
+        );
+        assert_eq!(instructions, profile.instructions());
+    }
+
+    #[test]
+    fn writes_the_report() {
+        let mut vm = profiled_vm();
+        interpret_in(&mut vm, FIB).unwrap();
+        let report = vm.profile().unwrap().to_string();
+        let (opcodes, functions) = report.split_once("\n\n").unwrap();
+
+        let mut opcodes = opcodes.lines();
+        assert_eq!(
+            opcodes
+                .next()
+                .unwrap()
+                .split_whitespace()
+                .collect::<Vec<_>>(),
+            ["opcode", "count", "share"]
+        );
+        let calls = opcodes
+            .map(|row| row.split_whitespace().collect::<Vec<_>>())
+            .find(|row| row[0] == "OP_CALL")
+            .unwrap();
+        // The script calls `fib` once, which calls itself 8 times.
+        assert_eq!(calls[1], "9");
+
+        let mut functions = functions.lines();
+        assert!(functions
+            .next()
+            .unwrap()
+            .starts_with("function        calls   instructions"));
+        let fib = functions
+            .map(|row| row.split_whitespace().collect::<Vec<_>>())
+            .find(|row| row[0] == "fib:2")
+            .unwrap();
+        assert_eq!(fib[1], "9");
+    }
+}
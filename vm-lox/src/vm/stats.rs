@@ -0,0 +1,163 @@
+use std::fmt::{self, Display};
+
+/// The execution statistics of a virtual machine, accumulated over all of its interpretations.
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    /// The inline cache lookups of the property gets, i.e. of a field or, if there is none, of a
+    /// method to bind.
+    pub property_cache: CacheStats,
+    /// The inline cache lookups of the method invocations (including the superclass ones).
+    pub invoke_cache: CacheStats,
+}
+
+/// The number of hits and misses of some inline caches.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheStats {
+    /// Records the outcome of a lookup.
+    pub(crate) fn record(&mut self, hit: bool) {
+        if hit {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+        }
+    }
+
+    /// Returns the total number of lookups.
+    pub fn lookups(&self) -> u64 {
+        self.hits + self.misses
+    }
+
+    /// Returns the fraction of the lookups which hit the cache, or None if there were none.
+    pub fn hit_rate(&self) -> Option<f64> {
+        (self.lookups() > 0).then(|| self.hits as f64 / self.lookups() as f64)
+    }
+}
+
+/// Writes the statistics as a table, as in:
+///
+/// ```text
+/// inline caches      lookups       hits   hit rate
+/// property gets           20         19      95.0%
+/// invocations           1000        998      99.8%
+/// ```
+impl Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<14} {:>12} {:>10} {:>10}",
+            "inline caches", "lookups", "hits", "hit rate"
+        )?;
+        let rows = [
+            ("property gets", self.property_cache),
+            ("invocations", self.invoke_cache),
+        ];
+        for (name, cache) in rows {
+            let hit_rate = match cache.hit_rate() {
+                Some(rate) => format!("{:.1}%", rate * 100.0),
+                None => "-".into(),
+            };
+            writeln!(
+                f,
+                "{name:<14} {:>12} {:>10} {hit_rate:>10}",
+                cache.lookups(),
+                cache.hits
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        pipeline::interpret_in,
+        vm::{LoxValue, Vm},
+    };
+
+    #[test]
+    fn counts_property_lookups() {
+        let mut vm = Vm::new();
+        let source = "
+            class P { init(x) { this.x = x; } get() { return this.x; } }
+            var p = P(1);
+            var sum = 0;
+            for (var i = 0; i < 10; i = i + 1) { sum = sum + p.x + p.get(); }
+            var bound = p.get;
+        ";
+        interpret_in(&mut vm, source).unwrap();
+        assert_eq!(vm.global("sum"), Some(LoxValue::Number(20.0)));
+        let stats = vm.stats();
+        // Only the first `p.x` and `this.x` miss, besides `p.get` (which finds no field).
+        assert_eq!(stats.property_cache.lookups(), 21);
+        assert_eq!(stats.property_cache.hits, 18);
+        assert_eq!(stats.invoke_cache.lookups(), 10);
+        assert_eq!(stats.invoke_cache.hits, 9);
+    }
+
+    #[test]
+    fn caches_the_last_class_of_polymorphic_call_sites() {
+        let mut vm = Vm::new();
+        let source = "
+            class A { name() { return \"A\"; } }
+            class B < A { name() { return \"B\"; } }
+            class C < A {}
+            fun name(object) { return object.name(); }
+            fun field() { return \"D\"; }
+            var d = A();
+            d.name = field;
+            var names = name(A()) + name(B()) + name(C()) + name(A()) + name(A()) + name(d);
+        ";
+        interpret_in(&mut vm, source).unwrap();
+        assert_eq!(vm.global("names"), Some(LoxValue::String("ABAAAD".into())));
+        // Only the last `A()` hits, the field of `d` isn't looked up as a method.
+        let stats = vm.stats();
+        assert_eq!(stats.invoke_cache.lookups(), 5);
+        assert_eq!(stats.invoke_cache.hits, 1);
+    }
+
+    #[test]
+    fn caches_fields_per_class() {
+        let mut vm = Vm::new();
+        let source = "
+            class P {}
+            class Q {}
+            var p = P(); p.x = 1; p.y = 2;
+            var q = Q(); q.x = 3;
+            fun x(object) { return object.x; }
+            var sum = x(p) + x(p) + x(q) + x(q) + x(p);
+        ";
+        interpret_in(&mut vm, source).unwrap();
+        assert_eq!(vm.global("sum"), Some(LoxValue::Number(9.0)));
+        let stats = vm.stats();
+        assert_eq!(stats.property_cache.lookups(), 5);
+        assert_eq!(stats.property_cache.hits, 2);
+    }
+
+    #[test]
+    fn clears_the_caches_of_freed_classes() {
+        let mut vm = Vm::new();
+        let source = "
+            class A { m() { return 1; } }
+            fun call(object) { return object.m(); }
+            call(A());
+        ";
+        interpret_in(&mut vm, source).unwrap();
+        let cache = |vm: &Vm| {
+            let name = vm.heap.find_interned("call").unwrap();
+            let closure = vm.globals.get(name).unwrap().as_object().unwrap();
+            let function = closure.as_closure().unwrap().function;
+            function.as_function().unwrap().chunk.caches[0].get()
+        };
+        vm.collect_garbage();
+        assert!(cache(&vm).is_some());
+
+        interpret_in(&mut vm, "A = nil;").unwrap();
+        vm.collect_garbage();
+        assert!(cache(&vm).is_none());
+    }
+}
@@ -0,0 +1,116 @@
+//! Runs the example scripts of the repository and compares their output with the expected one,
+//! stored in `tests/examples/<name>.out`.
+//!
+//! The tests cover the value representation selected by the features, hence they should be run
+//! with both of them:
+//!
+//! ```text
+//! cargo test -p vm-lox
+//! cargo test -p vm-lox --features nan-boxing
+//! ```
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+const EXAMPLES_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../examples");
+const EXPECTED_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/examples");
+
+/// Returns the paths of the example scripts.
+fn examples() -> Vec<PathBuf> {
+    let mut paths: Vec<_> = fs::read_dir(EXAMPLES_DIR)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "lox"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "No examples found in {EXAMPLES_DIR}");
+    paths
+}
+
+/// Returns the expected output of the given example.
+fn expected_output(example: &Path) -> String {
+    let name = example.file_stem().unwrap();
+    let path = Path::new(EXPECTED_DIR).join(name).with_extension("out");
+    fs::read_to_string(&path)
+        .unwrap_or_else(|_| panic!("Missing expected output {}", path.display()))
+}
+
+/// Runs `vm-lox` with the given arguments, and returns its output once it succeeded.
+fn run(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_vm-lox"))
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "`vm-lox {}` failed:\n{}",
+        args.join(" "),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Replaces the timings printed by the examples (as in `(elapsed 0.5s)`), which vary between runs.
+fn mask_timings(output: &str) -> String {
+    const PREFIX: &str = "(elapsed ";
+    let mut masked = String::with_capacity(output.len());
+    let mut rest = output;
+    while let Some(start) = rest.find(PREFIX) {
+        let end = start + rest[start..].find(')').unwrap();
+        masked.push_str(&rest[..start + PREFIX.len()]);
+        masked.push_str("<time>");
+        rest = &rest[end..];
+    }
+    masked.push_str(rest);
+    masked
+}
+
+/// Checks the output of each example when run with the given extra arguments.
+fn check_examples(args: &[&str]) {
+    for example in examples() {
+        let expected = expected_output(&example);
+        let mut args = args.to_vec();
+        args.push(example.to_str().unwrap());
+        assert_eq!(
+            mask_timings(&run(&args)),
+            expected,
+            "Unexpected output of `vm-lox {}`",
+            args.join(" ")
+        );
+    }
+}
+
+#[test]
+fn runs_examples() {
+    check_examples(&[]);
+}
+
+#[test]
+fn runs_optimized_examples() {
+    check_examples(&["-O1"]);
+}
+
+#[test]
+fn runs_examples_under_gc_stress() {
+    check_examples(&["--stress-gc"]);
+}
+
+#[test]
+fn runs_compiled_examples() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("examples");
+    fs::create_dir_all(&dir).unwrap();
+    for example in examples() {
+        let output = dir
+            .join(example.file_name().unwrap())
+            .with_extension("loxc");
+        let output = output.to_str().unwrap();
+        run(&["compile", example.to_str().unwrap(), "-o", output]);
+        assert_eq!(
+            mask_timings(&run(&["run", output])),
+            expected_output(&example)
+        );
+    }
+}
@@ -0,0 +1,49 @@
+//! Runs REPL sessions of `vm-lox` over the standard input, and checks their output.
+
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+/// Runs a REPL session with the given input, and returns its standard output.
+fn session(input: &str) -> String {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_vm-lox"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn prints_trailing_expressions() {
+    assert_eq!(session("1 + 2\n"), ">>> 3\n>>> \n");
+    assert_eq!(session("var a = \"x\";\na\n"), ">>> >>> x\n>>> \n");
+}
+
+#[test]
+fn continues_incomplete_inputs() {
+    assert_eq!(session("print 1 +\n2;\n"), ">>> ... 3\n>>> \n");
+    assert_eq!(session("if (true)\n4\n"), ">>> ... 4\n>>> \n");
+}
+
+#[test]
+fn disassembles_the_executed_code() {
+    let output = session(":disassemble\n3 * 4\n");
+    let listing = output
+        .strip_prefix(">>> Toggled `disassemble` option ON.\n>>> ")
+        .unwrap();
+    assert!(listing.starts_with("=== <script> ===\n"));
+    assert!(listing.contains("OP_MULTIPLY\n"));
+    assert!(listing.contains("OP_PRINT\n"));
+    assert!(listing.ends_with("12\n>>> \n"));
+}
@@ -0,0 +1,53 @@
+//! Runs a script of `vm-lox` with the `--trace` option, and checks the traced execution.
+
+use std::{fs, path::Path, process::Command};
+
+const SOURCE: &str = "\
+fun add(a, b) {
+  return a + b;
+}
+print add(1, 2);
+";
+
+/// The stack contents and the instruction listing printed (to the standard error) before each
+/// instruction is executed.
+const TRACE: &str = "          [ <fun <script>> ]
+0000     3 | OP_CLOSURE       0 (<fun add>)
+          [ <fun <script>> ][ <fun add> ]
+0002     3 | OP_DEFINE_GLOBAL 1 (\"add\")
+          [ <fun <script>> ]
+0004     4 | OP_GET_GLOBAL    1 (\"add\")
+          [ <fun <script>> ][ <fun add> ]
+0006     4 | OP_CONSTANT      2 (1)
+          [ <fun <script>> ][ <fun add> ][ 1 ]
+0008     4 | OP_CONSTANT      3 (2)
+          [ <fun <script>> ][ <fun add> ][ 1 ][ 2 ]
+0010     4 | OP_CALL          2
+          [ <fun <script>> ][ <fun add> ][ 1 ][ 2 ]
+0000     2 | OP_GET_LOCAL     1
+          [ <fun <script>> ][ <fun add> ][ 1 ][ 2 ][ 1 ]
+0002     2 | OP_GET_LOCAL     2
+          [ <fun <script>> ][ <fun add> ][ 1 ][ 2 ][ 1 ][ 2 ]
+0004     2 | OP_ADD
+          [ <fun <script>> ][ <fun add> ][ 1 ][ 2 ][ 3 ]
+0005     2 | OP_RETURN
+          [ <fun <script>> ][ 3 ]
+0012     4 | OP_PRINT
+          [ <fun <script>> ]
+0013     4 | OP_NIL
+          [ <fun <script>> ][ nil ]
+0014     4 | OP_RETURN
+";
+
+#[test]
+fn traces_the_stack_and_the_instructions() {
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("trace.lox");
+    fs::write(&path, SOURCE).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_vm-lox"))
+        .args(["--trace", path.to_str().unwrap()])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "3\n");
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), TRACE);
+}